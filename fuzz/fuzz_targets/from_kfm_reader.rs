@@ -0,0 +1,18 @@
+#![no_main]
+
+use kfme::bin::{Limits, StringEncoding};
+use kfme::source::SourceFile;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Decode with the default limits, as `SourceFile::from_kfm_reader` does for streams
+    let _ = SourceFile::from_kfm_reader(data);
+
+    // Decode with the size of the input as the total byte limit, as `SourceFile::load` does
+    // for files
+    let limits = Limits {
+        max_total_bytes: data.len() as u64,
+        ..Limits::default()
+    };
+    let _ = SourceFile::from_kfm_reader_with(data, limits, StringEncoding::default());
+});
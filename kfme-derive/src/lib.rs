@@ -0,0 +1,352 @@
+//! Derive macros for the `Encode` and `Decode` traits of `kfme::bin`.
+//!
+//! Fields are encoded and decoded in declaration order, each with its own `Encode`/`Decode`
+//! implementation. The following field attributes change how a field is handled:
+//!
+//! - `#[kfm(order = N)]` places the field at position `N` instead of its declaration position.
+//!   Fields without an explicit order keep their declaration position, and no two fields may
+//!   end up at the same position.
+//! - `#[kfm(rename = "name")]` names the field `name` in paths and error messages.
+//! - `#[kfm(if = "expr")]` makes an `Option` field present only if `expr` evaluates to `true`.
+//!   The expression can refer to preceding fields by name, as references. Encoding fails if the
+//!   field is `None` where it is present, or `Some` where it is not.
+//! - `#[kfm(since = V)]` and `#[kfm(before = V)]` make the field present only in format
+//!   versions from `V` on, or up to but excluding `V`. Where the field is absent, it decodes
+//!   to its default value, and only its default value can be encoded.
+//!
+//! The struct attribute `#[kfm(check = "path")]` calls `path(&self) -> anyhow::Result<()>`
+//! before encoding, so that values the format cannot represent are rejected.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, LitInt, LitStr, Type};
+
+#[proc_macro_derive(Encode, attributes(kfm))]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_encode(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(Decode, attributes(kfm))]
+pub fn derive_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_decode(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct Field {
+    ident: Ident,
+    span: proc_macro2::Span,
+    ty: Type,
+    name: String,
+    order: Option<usize>,
+    cond: Option<TokenStream2>,
+    since: Option<Ident>,
+    before: Option<Ident>,
+}
+
+impl Field {
+    fn parse(field: &syn::Field) -> syn::Result<Self> {
+        let ident = field
+            .ident
+            .clone()
+            .ok_or_else(|| syn::Error::new(field.span(), "expected a named field"))?;
+
+        let mut result = Self {
+            name: ident.to_string(),
+            ident,
+            span: field.span(),
+            ty: field.ty.clone(),
+            order: None,
+            cond: None,
+            since: None,
+            before: None,
+        };
+
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("kfm")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("order") {
+                    let lit: LitInt = meta.value()?.parse()?;
+                    result.order = Some(lit.base10_parse()?);
+                } else if meta.path.is_ident("rename") {
+                    let lit: LitStr = meta.value()?.parse()?;
+                    result.name = lit.value();
+                } else if meta.path.is_ident("if") {
+                    let lit: LitStr = meta.value()?.parse()?;
+                    result.cond = Some(lit.parse()?);
+                } else if meta.path.is_ident("since") {
+                    result.since = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("before") {
+                    result.before = Some(meta.value()?.parse()?);
+                } else {
+                    return Err(meta.error("unknown `kfm` attribute"));
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(result)
+    }
+
+    /// Condition under which the field is present in the given format version, if the field
+    /// is version-gated.
+    fn version_cond(&self) -> Option<TokenStream2> {
+        let conds: Vec<_> = self
+            .since
+            .iter()
+            .map(|v| quote! { opts.version >= ::kfme::source::FormatVersion::#v })
+            .chain(
+                self.before
+                    .iter()
+                    .map(|v| quote! { opts.version < ::kfme::source::FormatVersion::#v }),
+            )
+            .collect();
+
+        if conds.is_empty() {
+            None
+        } else {
+            Some(quote! { #( #conds )&&* })
+        }
+    }
+}
+
+/// Parses the `check` attribute of a struct, if any.
+fn parse_check(input: &DeriveInput) -> syn::Result<Option<syn::Path>> {
+    let mut check = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("kfm")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("check") {
+                let lit: LitStr = meta.value()?.parse()?;
+                check = Some(lit.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("unknown `kfm` attribute"))
+            }
+        })?;
+    }
+
+    Ok(check)
+}
+
+/// Parses the fields of a struct, sorted in encoding order.
+fn parse_fields(input: &DeriveInput) -> syn::Result<Vec<Field>> {
+    let fields = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(f) => &f.named,
+            _ => return Err(syn::Error::new(input.span(), "expected named fields")),
+        },
+        _ => return Err(syn::Error::new(input.span(), "expected a struct")),
+    };
+
+    let mut result = fields
+        .iter()
+        .map(Field::parse)
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    // Fields without an explicit order keep their declaration position
+    let mut keyed: Vec<_> = result
+        .drain(..)
+        .enumerate()
+        .map(|(i, f)| (f.order.unwrap_or(i), f))
+        .collect();
+    keyed.sort_by_key(|(order, _)| *order);
+
+    for pair in keyed.windows(2) {
+        let ((order, first), (next_order, second)) = (&pair[0], &pair[1]);
+        if order == next_order {
+            let msg = format!(
+                "`{}` has the same order {} as `{}`",
+                second.ident, order, first.ident
+            );
+            return Err(syn::Error::new(second.span, msg));
+        }
+    }
+
+    result.extend(keyed.into_iter().map(|(_, f)| f));
+    Ok(result)
+}
+
+fn expand_encode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = parse_fields(input)?;
+    let check = parse_check(input)?.map(|path| quote! { #path(self)?; });
+    let struct_ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let idents: Vec<_> = fields.iter().map(|f| &f.ident).collect();
+
+    let writes = fields.iter().map(|f| {
+        let ident = &f.ident;
+        let ty = &f.ty;
+        let version_cond = f.version_cond();
+        let write_ctx = format!("write `{}`", f.name);
+        let unsupported_msg = format!("version `{{:?}}` does not support `{}`", f.name);
+
+        let write = match &f.cond {
+            // All fields are in scope as references, like preceding fields are when decoding
+            Some(cond) => {
+                let missing_msg = format!("`{}` is required", f.name);
+                let unexpected_msg = format!("`{}` is not stored", f.name);
+                quote! {
+                    match (#cond, #ident) {
+                        (true, ::std::option::Option::Some(v)) => {
+                            writer.write_value::<_, O>(v, opts).context(#write_ctx)?;
+                        }
+                        (true, ::std::option::Option::None) => ::anyhow::bail!(#missing_msg),
+                        (false, ::std::option::Option::Some(_)) => {
+                            ::anyhow::bail!(#unexpected_msg)
+                        }
+                        (false, ::std::option::Option::None) => {}
+                    }
+                }
+            }
+            None => quote! {
+                writer.write_value::<_, O>(#ident, opts).context(#write_ctx)?;
+            },
+        };
+
+        match version_cond {
+            Some(version_cond) => quote! {
+                if #version_cond {
+                    #write
+                } else if *#ident != <#ty as ::std::default::Default>::default() {
+                    ::anyhow::bail!(#unsupported_msg, opts.version);
+                }
+            },
+            None => write,
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::kfme::bin::Encode for #struct_ident #ty_generics #where_clause {
+            fn encode<W, O>(&self, writer: &mut W, opts: &::kfme::bin::Options) -> ::anyhow::Result<()>
+            where
+                W: ::std::io::Write,
+                O: ::byteorder::ByteOrder,
+            {
+                use ::anyhow::Context as _;
+                use ::kfme::bin::WriteValueExt as _;
+
+                #check
+                let Self { #( #idents ),* } = self;
+                #( #writes )*
+
+                ::std::result::Result::Ok(())
+            }
+        }
+    })
+}
+
+fn expand_decode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = parse_fields(input)?;
+    let struct_ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut reads = Vec::new();
+    for (i, f) in fields.iter().enumerate() {
+        let ident = &f.ident;
+        let ty = &f.ty;
+        let name = &f.name;
+        let version_cond = f.version_cond();
+
+        let read = match &f.cond {
+            Some(cond) => {
+                // Expose preceding fields to the condition as references
+                let preceding: Vec<_> = fields[..i].iter().map(|f| &f.ident).collect();
+                let cond_ident = format_ident!("__{}_present", ident);
+                quote! {
+                    let #cond_ident = {
+                        #( #[allow(unused_variables)] let #preceding = &#preceding; )*
+                        #cond
+                    };
+                    if #cond_ident {
+                        ::std::option::Option::Some(reader.read_field::<_, O>(#name, opts)?)
+                    } else {
+                        ::std::option::Option::None
+                    }
+                }
+            }
+            None => quote! { reader.read_field::<_, O>(#name, opts)? },
+        };
+
+        reads.push(match version_cond {
+            Some(version_cond) => quote! {
+                let #ident: #ty = if #version_cond {
+                    #read
+                } else {
+                    ::std::default::Default::default()
+                };
+            },
+            None => quote! {
+                let #ident: #ty = { #read };
+            },
+        });
+    }
+
+    let idents: Vec<_> = fields.iter().map(|f| &f.ident).collect();
+
+    Ok(quote! {
+        impl #impl_generics ::kfme::bin::Decode for #struct_ident #ty_generics #where_clause {
+            fn decode<R, O>(reader: &mut R, opts: &::kfme::bin::Options) -> ::anyhow::Result<Self>
+            where
+                R: ::kfme::bin::ReadValueExt,
+                O: ::byteorder::ByteOrder,
+            {
+                #( #reads )*
+
+                ::std::result::Result::Ok(Self { #( #idents ),* })
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_fields;
+    use syn::{parse_quote, DeriveInput};
+
+    #[test]
+    fn test_parse_fields_order() {
+        let input: DeriveInput = parse_quote! {
+            struct S {
+                #[kfm(order = 2)]
+                a: u8,
+                b: u8,
+                #[kfm(order = 0)]
+                c: u8,
+            }
+        };
+        let fields = parse_fields(&input).unwrap();
+        let names: Vec<_> = fields.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(vec!["c", "b", "a"], names);
+    }
+
+    #[test]
+    fn test_parse_fields_duplicate_order() {
+        let input: DeriveInput = parse_quote! {
+            struct S {
+                #[kfm(order = 1)]
+                a: u8,
+                #[kfm(order = 1)]
+                b: u8,
+            }
+        };
+        let err = parse_fields(&input).err().unwrap();
+        assert_eq!("`b` has the same order 1 as `a`", err.to_string());
+
+        // So does an explicit order that is the declaration position of another field
+        let input: DeriveInput = parse_quote! {
+            struct S {
+                a: u8,
+                #[kfm(order = 0)]
+                b: u8,
+            }
+        };
+        let err = parse_fields(&input).err().unwrap();
+        assert_eq!("`b` has the same order 0 as `a`", err.to_string());
+    }
+}
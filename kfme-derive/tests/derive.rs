@@ -0,0 +1,105 @@
+use byteorder::LittleEndian;
+use kfme::bin::{Decode, Encode, Options, ReadValueExt, TrackingReader};
+use kfme::source::FormatVersion;
+
+#[derive(Encode, Decode, Debug, Default, PartialEq)]
+struct Ordered {
+    #[kfm(order = 2)]
+    a: u8,
+    b: u8,
+    #[kfm(order = 0)]
+    c: u8,
+}
+
+#[derive(Encode, Decode, Debug, Default, PartialEq)]
+struct Conditional {
+    flag: u8,
+    #[kfm(if = "*flag != 0")]
+    value: Option<u8>,
+}
+
+#[derive(Encode, Decode, Debug, Default, PartialEq)]
+struct Versioned {
+    #[kfm(since = V2_1_0_0b)]
+    new: u8,
+    #[kfm(before = V2_0_0_0b)]
+    old: u8,
+    always: u8,
+}
+
+fn encode<T>(value: &T, version: FormatVersion) -> anyhow::Result<Vec<u8>>
+where
+    T: Encode,
+{
+    let opts = Options {
+        version,
+        ..Default::default()
+    };
+    let mut buf = Vec::new();
+    value.encode::<_, LittleEndian>(&mut buf, &opts)?;
+    Ok(buf)
+}
+
+fn decode<T>(bytes: &[u8], version: FormatVersion) -> T
+where
+    T: Decode,
+{
+    let opts = Options {
+        version,
+        ..Default::default()
+    };
+    let mut reader = TrackingReader::new(bytes, bytes.len() as u64);
+    let value = reader.read_value::<T, LittleEndian>(&opts).unwrap();
+    assert_eq!(bytes.len() as u64, reader.position());
+    value
+}
+
+#[test]
+fn test_order() {
+    let value = Ordered { a: 1, b: 2, c: 3 };
+    let bytes = encode(&value, FormatVersion::default()).unwrap();
+    assert_eq!(vec![3, 2, 1], bytes);
+    assert_eq!(value, decode(&bytes, FormatVersion::default()));
+}
+
+#[test]
+fn test_if() {
+    let conditional = |flag, value| Conditional { flag, value };
+    let version = FormatVersion::default();
+    for (value, expected) in [
+        (conditional(1, Some(7)), vec![1, 7]),
+        (conditional(0, None), vec![0]),
+    ] {
+        let bytes = encode(&value, version).unwrap();
+        assert_eq!(expected, bytes);
+        assert_eq!(value, decode(&bytes, version));
+    }
+
+    let err = encode(&conditional(1, None), version).unwrap_err();
+    assert_eq!("`value` is required", err.to_string());
+    let err = encode(&conditional(0, Some(7)), version).unwrap_err();
+    assert_eq!("`value` is not stored", err.to_string());
+}
+
+#[test]
+fn test_since_and_before() {
+    let versioned = |new, old, always| Versioned { new, old, always };
+    let cases = [
+        (FormatVersion::V1_2_4b, versioned(0, 2, 3), vec![2, 3]),
+        (FormatVersion::V2_0_0_0b, versioned(0, 0, 3), vec![3]),
+        (FormatVersion::V2_1_0_0b, versioned(1, 0, 3), vec![1, 3]),
+    ];
+    for (version, value, expected) in cases {
+        let bytes = encode(&value, version).unwrap();
+        assert_eq!(expected, bytes, "{:?}", version);
+        assert_eq!(value, decode(&bytes, version));
+    }
+
+    // Fields absent from a version can only hold their default value
+    let err = encode(&versioned(1, 0, 3), FormatVersion::V2_0_0_0b).unwrap_err();
+    assert_eq!(
+        "version `V2_0_0_0b` does not support `new`",
+        err.to_string()
+    );
+    assert!(encode(&versioned(0, 2, 3), FormatVersion::V2_2_0_0b).is_err());
+}
@@ -1,10 +1,134 @@
+use crate::source::FormatVersion;
 use anyhow::{bail, Context, Result};
 use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io::{Read, Write};
 
+pub use kfme_derive::{Decode, Encode};
+
+/// Options that control how values are encoded into and decoded from KFM binary format.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    /// Revision of the KFM format being read or written.
+    pub version: FormatVersion,
+
+    /// Limits enforced while decoding.
+    pub limits: Limits,
+
+    /// Character encoding of strings.
+    pub string_encoding: StringEncoding,
+}
+
+/// Limits that keep corrupt or malicious input from making the decoder allocate unbounded
+/// amounts of memory.
+///
+/// Length prefixes are also checked against the input that remains to be read, so that a
+/// truncated file fails before anything is allocated for it. The size of a stream is not known
+/// up front, so the remaining input of a stream is bounded by `max_total_bytes` instead.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum length of a single string, in bytes.
+    pub max_string_len: usize,
+
+    /// Maximum number of items in a single sequence.
+    pub max_items: usize,
+
+    /// Maximum number of bytes read from the input.
+    pub max_total_bytes: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_string_len: 4 * 1024,
+            max_items: 64 * 1024,
+            max_total_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Character encodings of strings in KFM files.
+///
+/// Every encoding maps losslessly between bytes and Rust strings, so that any string that can
+/// be decoded can be encoded back to the same bytes.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum StringEncoding {
+    /// UTF-8; bytes that are not valid UTF-8 are rejected.
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+
+    /// Only 7-bit ASCII; any other byte or character is rejected.
+    Ascii,
+
+    /// ISO-8859-1, where every byte maps to the character of the same code point.
+    #[serde(rename = "latin-1")]
+    Latin1,
+
+    /// Windows code page 1252. The five bytes it leaves undefined map to the C1 control
+    /// characters of the same code point, as in Latin-1.
+    #[serde(rename = "windows-1252")]
+    Windows1252,
+}
+
+/// Characters of Windows-1252 bytes `0x80` to `0x9F`.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+impl StringEncoding {
+    /// Converts encoded bytes to a string.
+    pub fn decode(self, bytes: &[u8]) -> Result<String> {
+        match self {
+            Self::Utf8 => Ok(std::str::from_utf8(bytes)?.to_string()),
+            _ => bytes.iter().map(|b| self.decode_byte(*b)).collect(),
+        }
+    }
+
+    /// Converts a string to encoded bytes.
+    pub fn encode(self, s: &str) -> Result<Vec<u8>> {
+        if self == Self::Utf8 {
+            return Ok(s.as_bytes().to_vec());
+        }
+
+        s.chars()
+            .map(|c| self.encode_char(c))
+            .collect::<Option<_>>()
+            .with_context(|| format!("`{}` cannot be encoded as {:?}", s, self))
+    }
+
+    fn decode_byte(self, b: u8) -> Result<char> {
+        match (self, b) {
+            (_, 0x00..=0x7F) => Ok(b as char),
+            (Self::Ascii, _) => bail!("byte {:#04x} is not ascii", b),
+            (Self::Windows1252, 0x80..=0x9F) => Ok(WINDOWS_1252_HIGH[b as usize - 0x80]),
+            _ => Ok(b as char),
+        }
+    }
+
+    fn encode_char(self, c: char) -> Option<u8> {
+        match (self, c as u32) {
+            (_, 0x00..=0x7F) => Some(c as u8),
+            (Self::Ascii, _) => None,
+            (Self::Latin1, 0x80..=0xFF) => Some(c as u8),
+            (Self::Windows1252, 0xA0..=0xFF) => Some(c as u8),
+            (Self::Windows1252, _) => WINDOWS_1252_HIGH
+                .iter()
+                .position(|h| *h == c)
+                .map(|i| 0x80 + i as u8),
+            _ => None,
+        }
+    }
+}
+
 /// Defines how an object can be encoded into KFM binary format.
 pub trait Encode {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
+    fn encode<W, O>(&self, writer: &mut W, opts: &Options) -> Result<()>
     where
         W: Write,
         O: ByteOrder;
@@ -14,7 +138,7 @@ impl<T> Encode for [T]
 where
     T: Encode,
 {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
+    fn encode<W, O>(&self, writer: &mut W, opts: &Options) -> Result<()>
     where
         W: Write,
         O: ByteOrder,
@@ -26,7 +150,7 @@ where
 
         for (i, item) in self.iter().enumerate() {
             writer
-                .write_value::<T, O>(item)
+                .write_value::<T, O>(item, opts)
                 .with_context(|| format!("write item at index {}", i))?;
         }
 
@@ -34,26 +158,87 @@ where
     }
 }
 
+impl<T> Encode for Vec<T>
+where
+    T: Encode,
+{
+    fn encode<W, O>(&self, writer: &mut W, opts: &Options) -> Result<()>
+    where
+        W: Write,
+        O: ByteOrder,
+    {
+        self.as_slice().encode::<_, O>(writer, opts)
+    }
+}
+
 impl Encode for String {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
+    fn encode<W, O>(&self, writer: &mut W, opts: &Options) -> Result<()>
     where
         W: Write,
         O: ByteOrder,
     {
-        if !self.is_ascii() {
-            bail!("`{}` is not ascii", self);
-        }
+        let bytes = opts.string_encoding.encode(self)?;
 
-        writer.write_u32::<O>(self.len() as u32)?;
-        writer.write_all(self.as_bytes())?;
+        writer.write_u32::<O>(bytes.len() as u32)?;
+        writer.write_all(&bytes)?;
 
         Ok(())
     }
 }
 
+impl Encode for u8 {
+    fn encode<W, O>(&self, writer: &mut W, _opts: &Options) -> Result<()>
+    where
+        W: Write,
+        O: ByteOrder,
+    {
+        Ok(writer.write_u8(*self)?)
+    }
+}
+
+impl Encode for u16 {
+    fn encode<W, O>(&self, writer: &mut W, _opts: &Options) -> Result<()>
+    where
+        W: Write,
+        O: ByteOrder,
+    {
+        Ok(writer.write_u16::<O>(*self)?)
+    }
+}
+
+impl Encode for u32 {
+    fn encode<W, O>(&self, writer: &mut W, _opts: &Options) -> Result<()>
+    where
+        W: Write,
+        O: ByteOrder,
+    {
+        Ok(writer.write_u32::<O>(*self)?)
+    }
+}
+
+impl Encode for i32 {
+    fn encode<W, O>(&self, writer: &mut W, _opts: &Options) -> Result<()>
+    where
+        W: Write,
+        O: ByteOrder,
+    {
+        Ok(writer.write_i32::<O>(*self)?)
+    }
+}
+
+impl Encode for f32 {
+    fn encode<W, O>(&self, writer: &mut W, _opts: &Options) -> Result<()>
+    where
+        W: Write,
+        O: ByteOrder,
+    {
+        Ok(writer.write_f32::<O>(*self)?)
+    }
+}
+
 /// Provides functionality for writing KFM binary-encodable data.
 pub trait WriteValueExt: Write {
-    fn write_value<T, O>(&mut self, data: &T) -> Result<()>
+    fn write_value<T, O>(&mut self, data: &T, opts: &Options) -> Result<()>
     where
         T: Encode + ?Sized,
         O: ByteOrder;
@@ -63,39 +248,56 @@ impl<W> WriteValueExt for W
 where
     W: Write,
 {
-    fn write_value<T, O>(&mut self, data: &T) -> Result<()>
+    fn write_value<T, O>(&mut self, data: &T, opts: &Options) -> Result<()>
     where
         T: Encode + ?Sized,
         O: ByteOrder,
     {
-        data.encode::<_, O>(self)
+        data.encode::<_, O>(self, opts)
     }
 }
 
 /// Defines how an object can be decoded from KFM binary format.
 pub trait Decode: Sized {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
+    fn decode<R, O>(reader: &mut R, opts: &Options) -> Result<Self>
     where
-        R: Read,
+        R: ReadValueExt,
         O: ByteOrder;
 }
 
+/// Maximum number of items allocated for a sequence before any of them is read.
+const MAX_INITIAL_CAPACITY: usize = 1024;
+
 impl<T> Decode for Vec<T>
 where
     T: Decode,
 {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
+    fn decode<R, O>(reader: &mut R, opts: &Options) -> Result<Self>
     where
-        R: Read,
+        R: ReadValueExt,
         O: ByteOrder,
     {
-        let num_items = reader.read_u32::<O>().context("read length specifier")? as usize;
+        let num_items = reader.read_value::<u32, O>(opts)? as usize;
+        if num_items > opts.limits.max_items {
+            bail!(
+                "item count {} exceeds limit of {}",
+                num_items,
+                opts.limits.max_items
+            );
+        }
+        if num_items as u64 > reader.remaining() {
+            bail!(
+                "item count {} exceeds remaining input of {} bytes",
+                num_items,
+                reader.remaining()
+            );
+        }
 
-        let mut items = Vec::with_capacity(num_items);
+        // The remaining input of a stream is only bounded by `max_total_bytes`, so a truncated
+        // stream may claim many more items than it holds; the vector grows as items are read
+        let mut items = Vec::with_capacity(num_items.min(MAX_INITIAL_CAPACITY));
         for i in 0..num_items {
-            let item = reader
-                .read_value::<T, O>()
-                .with_context(|| format!("read item at index {}", i))?;
+            let item = reader.read_item::<T, O>(i, opts)?;
             items.push(item);
         }
 
@@ -104,38 +306,324 @@ where
 }
 
 impl Decode for String {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
+    fn decode<R, O>(reader: &mut R, opts: &Options) -> Result<Self>
     where
-        R: Read,
+        R: ReadValueExt,
         O: ByteOrder,
     {
         let len = reader.read_u32::<O>()? as usize;
+        if len > opts.limits.max_string_len {
+            bail!(
+                "string length {} exceeds limit of {}",
+                len,
+                opts.limits.max_string_len
+            );
+        }
+        if len as u64 > reader.remaining() {
+            bail!(
+                "string length {} exceeds remaining input of {} bytes",
+                len,
+                reader.remaining()
+            );
+        }
 
         let mut buf = vec![0u8; len];
         reader.read_exact(&mut buf)?;
 
-        let s = String::from_utf8(buf)?;
+        let s = opts.string_encoding.decode(&buf)?;
         Ok(s)
     }
 }
 
+impl Decode for u8 {
+    fn decode<R, O>(reader: &mut R, _opts: &Options) -> Result<Self>
+    where
+        R: ReadValueExt,
+        O: ByteOrder,
+    {
+        Ok(reader.read_u8()?)
+    }
+}
+
+impl Decode for u16 {
+    fn decode<R, O>(reader: &mut R, _opts: &Options) -> Result<Self>
+    where
+        R: ReadValueExt,
+        O: ByteOrder,
+    {
+        Ok(reader.read_u16::<O>()?)
+    }
+}
+
+impl Decode for u32 {
+    fn decode<R, O>(reader: &mut R, _opts: &Options) -> Result<Self>
+    where
+        R: ReadValueExt,
+        O: ByteOrder,
+    {
+        Ok(reader.read_u32::<O>()?)
+    }
+}
+
+impl Decode for i32 {
+    fn decode<R, O>(reader: &mut R, _opts: &Options) -> Result<Self>
+    where
+        R: ReadValueExt,
+        O: ByteOrder,
+    {
+        Ok(reader.read_i32::<O>()?)
+    }
+}
+
+impl Decode for f32 {
+    fn decode<R, O>(reader: &mut R, _opts: &Options) -> Result<Self>
+    where
+        R: ReadValueExt,
+        O: ByteOrder,
+    {
+        Ok(reader.read_f32::<O>()?)
+    }
+}
+
+/// An error that occurred while decoding a value, along with where it occurred.
+#[derive(Debug)]
+pub struct DecodeError {
+    /// Byte offset at which the value that failed to decode starts.
+    pub offset: u64,
+
+    /// Path of the value that failed to decode, e.g. `anims[12].trans[3].ext`.
+    pub path: String,
+
+    source: anyhow::Error,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "read `{}` at offset {:#x}", self.path, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
 /// Provides functionality for reading KFM binary-decodable data.
+///
+/// Keeps track of the stream position and of the path of the value being decoded. Errors
+/// returned while decoding are wrapped in a `DecodeError` pointing at the innermost value that
+/// failed.
 pub trait ReadValueExt: Read + Sized {
-    fn read_value<T, O>(&mut self) -> Result<T>
+    /// Returns the number of bytes read so far.
+    fn position(&self) -> u64;
+
+    /// Returns the number of bytes that may still be read.
+    fn remaining(&self) -> u64;
+
+    /// Reads a value at the current path.
+    fn read_value<T, O>(&mut self, opts: &Options) -> Result<T>
+    where
+        T: Decode,
+        O: ByteOrder;
+
+    /// Reads the field `name` of the value at the current path.
+    fn read_field<T, O>(&mut self, name: &'static str, opts: &Options) -> Result<T>
+    where
+        T: Decode,
+        O: ByteOrder;
+
+    /// Reads the item at `index` of the sequence at the current path.
+    fn read_item<T, O>(&mut self, index: usize, opts: &Options) -> Result<T>
     where
         T: Decode,
         O: ByteOrder;
 }
 
-impl<R> ReadValueExt for R
+#[derive(Debug, Clone, Copy)]
+enum PathSegment {
+    Field(&'static str),
+    Index(usize),
+}
+
+/// A reader that keeps track of what is being decoded from it.
+///
+/// Fails once more than `max_len` bytes are read.
+pub struct TrackingReader<R> {
+    inner: R,
+    position: u64,
+    max_len: u64,
+    path: Vec<PathSegment>,
+}
+
+impl<R> TrackingReader<R>
+where
+    R: Read,
+{
+    pub fn new(inner: R, max_len: u64) -> Self {
+        Self {
+            inner,
+            position: 0,
+            max_len,
+            path: Vec::new(),
+        }
+    }
+
+    /// Returns the path of the value being decoded.
+    pub fn path(&self) -> String {
+        let mut result = String::new();
+        for segment in self.path.iter() {
+            match segment {
+                PathSegment::Field(name) if result.is_empty() => result.push_str(name),
+                PathSegment::Field(name) => {
+                    result.push('.');
+                    result.push_str(name);
+                }
+                PathSegment::Index(index) => result.push_str(&format!("[{}]", index)),
+            }
+        }
+        result
+    }
+
+    /// Reads the field `name` with the given function.
+    ///
+    /// Useful for fields whose validation is not part of a `Decode` implementation.
+    pub fn read_field_with<T, F>(&mut self, name: &'static str, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        self.read_segment(PathSegment::Field(name), f)
+    }
+
+    fn read_segment<T, F>(&mut self, segment: PathSegment, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        self.path.push(segment);
+        let result = self.read_tracked(f);
+        self.path.pop();
+        result
+    }
+
+    fn read_tracked<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        let offset = self.position;
+        f(self).map_err(|e| {
+            if e.is::<DecodeError>() {
+                e
+            } else {
+                DecodeError {
+                    offset,
+                    path: self.path(),
+                    source: e,
+                }
+                .into()
+            }
+        })
+    }
+}
+
+impl<R> Read for TrackingReader<R>
 where
     R: Read,
 {
-    fn read_value<T, O>(&mut self) -> Result<T>
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.max_len - self.position;
+        if remaining == 0 && !buf.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "input exceeds limit of {} bytes",
+                self.max_len
+            )));
+        }
+
+        let max_n = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max_n])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> ReadValueExt for TrackingReader<R>
+where
+    R: Read,
+{
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn remaining(&self) -> u64 {
+        self.max_len - self.position
+    }
+
+    fn read_value<T, O>(&mut self, opts: &Options) -> Result<T>
     where
         T: Decode,
         O: ByteOrder,
     {
-        T::decode::<_, O>(self)
+        self.read_tracked(|r| T::decode::<_, O>(r, opts))
+    }
+
+    fn read_field<T, O>(&mut self, name: &'static str, opts: &Options) -> Result<T>
+    where
+        T: Decode,
+        O: ByteOrder,
+    {
+        self.read_segment(PathSegment::Field(name), |r| T::decode::<_, O>(r, opts))
+    }
+
+    fn read_item<T, O>(&mut self, index: usize, opts: &Options) -> Result<T>
+    where
+        T: Decode,
+        O: ByteOrder,
+    {
+        self.read_segment(PathSegment::Index(index), |r| T::decode::<_, O>(r, opts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limits, Options, ReadValueExt, StringEncoding, TrackingReader};
+    use byteorder::LittleEndian;
+
+    #[test]
+    fn test_string_encoding_lossless() {
+        for encoding in [StringEncoding::Latin1, StringEncoding::Windows1252] {
+            let bytes: Vec<u8> = (0..=255).collect();
+            let s = encoding.decode(&bytes).unwrap();
+            assert_eq!(256, s.chars().count());
+            assert_eq!(bytes, encoding.encode(&s).unwrap());
+        }
+
+        let bytes: Vec<u8> = (0..=127).collect();
+        let s = StringEncoding::Ascii.decode(&bytes).unwrap();
+        assert_eq!(bytes, StringEncoding::Ascii.encode(&s).unwrap());
+    }
+
+    #[test]
+    fn test_string_encoding_chars() {
+        let s = "./anims/caf\u{e9}_\u{20ac}.kf";
+        let bytes = StringEncoding::Windows1252.encode(s).unwrap();
+        assert_eq!(b"./anims/caf\xe9_\x80.kf".as_slice(), bytes);
+        assert_eq!(s, StringEncoding::Windows1252.decode(&bytes).unwrap());
+
+        // The euro sign only exists in Windows-1252
+        assert!(StringEncoding::Latin1.encode(s).is_err());
+        assert!(StringEncoding::Ascii.encode(s).is_err());
+        assert!(StringEncoding::Ascii.decode(&bytes).is_err());
+
+        // Latin-1 maps `0x80` to a control character
+        assert_eq!("\u{80}", StringEncoding::Latin1.decode(&[0x80]).unwrap());
+    }
+
+    #[test]
+    fn test_truncated_stream() {
+        // A stream that claims the most items allowed but ends after the count
+        let bytes = 65536u32.to_le_bytes();
+        let mut reader = TrackingReader::new(&bytes[..], Limits::default().max_total_bytes);
+        let result = reader.read_value::<Vec<u32>, LittleEndian>(&Options::default());
+        assert!(result.is_err());
+        assert_eq!(4, reader.position());
     }
 }
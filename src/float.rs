@@ -0,0 +1,200 @@
+//! Handling of `f32` values in JSON and TOML.
+//!
+//! JSON has no representation for NaN or infinities, so these are written as the strings
+//! `"NaN"`, `"inf"` and `"-inf"` instead. Fields that may hold such values accept both numbers
+//! and these strings when read.
+//!
+//! Both formats store numbers as `f64`, so floats are written as the shortest number that
+//! reads back as the same `f32`, rather than with the digits of their `f64` widening.
+
+use anyhow::{bail, Result};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+
+/// Deserializes an `f32` from a number or from one of the strings for non-finite values.
+pub fn deserialize<'de, D>(deserializer: D) -> Result<f32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(FloatVisitor)
+}
+
+/// Deserializes an optional `f32` like `deserialize`, for fields of patches that may be left
+/// out. Such fields also need `#[serde(default)]`.
+pub fn deserialize_option<'de, D>(deserializer: D) -> Result<Option<f32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Float(#[serde(deserialize_with = "deserialize")] f32);
+
+    Ok(Option::<Float>::deserialize(deserializer)?.map(|Float(f)| f))
+}
+
+struct FloatVisitor;
+
+impl Visitor<'_> for FloatVisitor {
+    type Value = f32;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number, \"NaN\", \"inf\" or \"-inf\"")
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<f32, E> {
+        Ok(v as f32)
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<f32, E> {
+        Ok(v as f32)
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<f32, E> {
+        Ok(v as f32)
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<f32, E>
+    where
+        E: de::Error,
+    {
+        match v.parse::<f32>() {
+            Ok(f) if !f.is_finite() => Ok(f),
+            _ => Err(E::invalid_value(de::Unexpected::Str(v), &self)),
+        }
+    }
+}
+
+/// Converts a value to JSON, writing non-finite numbers as strings.
+pub fn to_json_value<T>(value: &T) -> Result<serde_json::Value>
+where
+    T: Serialize,
+{
+    // Unlike `serde_json::Value`, `serde_yaml::Value` can hold non-finite numbers
+    let value = serde_yaml::to_value(value)?;
+    yaml_to_json(value)
+}
+
+fn yaml_to_json(value: serde_yaml::Value) -> Result<serde_json::Value> {
+    use serde_json::Value as Json;
+    use serde_yaml::Value as Yaml;
+
+    let json = match value {
+        Yaml::Null => Json::Null,
+        Yaml::Bool(b) => Json::Bool(b),
+        Yaml::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+            (Some(u), _, _) => Json::from(u),
+            (_, Some(i), _) => Json::from(i),
+            (_, _, Some(f)) if f.is_finite() => Json::from(shortest(f)),
+            (_, _, Some(f)) => Json::String((f as f32).to_string()),
+            _ => bail!("unsupported number `{}`", n),
+        },
+        Yaml::String(s) => Json::String(s),
+        Yaml::Sequence(seq) => {
+            Json::Array(seq.into_iter().map(yaml_to_json).collect::<Result<_>>()?)
+        }
+        Yaml::Mapping(map) => {
+            let mut obj = serde_json::Map::new();
+            for (k, v) in map {
+                let Yaml::String(k) = k else {
+                    bail!("unsupported mapping key `{:?}`", k);
+                };
+                obj.insert(k, yaml_to_json(v)?);
+            }
+            Json::Object(obj)
+        }
+        Yaml::Tagged(tagged) => yaml_to_json(tagged.value)?,
+    };
+
+    Ok(json)
+}
+
+/// Converts a value to TOML.
+pub fn to_toml_value<T>(value: &T) -> Result<toml::Value>
+where
+    T: Serialize,
+{
+    let mut value = toml::Value::try_from(value)?;
+    shorten_toml_floats(&mut value);
+    Ok(value)
+}
+
+fn shorten_toml_floats(value: &mut toml::Value) {
+    match value {
+        toml::Value::Float(f) if f.is_finite() => *f = shortest(*f),
+        toml::Value::Array(arr) => arr.iter_mut().for_each(shorten_toml_floats),
+        toml::Value::Table(table) => table.iter_mut().for_each(|(_, v)| shorten_toml_floats(v)),
+        _ => {}
+    }
+}
+
+/// Returns the `f64` with the fewest digits that converts to the same `f32` as `f`.
+fn shortest(f: f64) -> f64 {
+    let f = f as f32;
+    format!("{:?}", f).parse().unwrap_or(f as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Value {
+        #[serde(deserialize_with = "super::deserialize")]
+        x: f32,
+    }
+
+    #[test]
+    fn test_non_finite_json() {
+        for x in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 0.2] {
+            let json = super::to_json_value(&Value { x }).unwrap().to_string();
+            let decoded: Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(x.to_bits(), decoded.x.to_bits(), "{}", json);
+        }
+
+        let json = super::to_json_value(&Value { x: f32::NAN }).unwrap();
+        assert_eq!(r#"{"x":"NaN"}"#, json.to_string());
+
+        let json = super::to_json_value(&Value { x: 0.2 }).unwrap();
+        assert_eq!(r#"{"x":0.2}"#, json.to_string());
+        assert!(serde_json::from_str::<Value>(r#"{"x":"1.5"}"#).is_err());
+    }
+
+    #[test]
+    fn test_toml_floats() {
+        let toml = super::to_toml_value(&Value { x: 0.2 }).unwrap();
+        assert_eq!("x = 0.2\n", toml::to_string(&toml).unwrap());
+
+        let toml = super::to_toml_value(&Value { x: f32::NAN }).unwrap();
+        let toml = toml::to_string(&toml).unwrap();
+        let decoded: Value = toml::from_str(&toml).unwrap();
+        assert!(decoded.x.is_nan());
+    }
+
+    #[test]
+    fn test_non_finite_yaml() {
+        let decoded: Value = serde_yaml::from_str("x: .nan").unwrap();
+        assert!(decoded.x.is_nan());
+
+        let yaml = serde_yaml::to_string(&Value { x: f32::NAN }).unwrap();
+        assert_eq!("x: .nan\n", yaml);
+    }
+
+    #[test]
+    fn test_option() {
+        #[derive(Deserialize)]
+        struct OptionValue {
+            #[serde(default, deserialize_with = "super::deserialize_option")]
+            x: Option<f32>,
+        }
+
+        let decoded: OptionValue = serde_json::from_str(r#"{"x":"-inf"}"#).unwrap();
+        assert_eq!(Some(f32::NEG_INFINITY), decoded.x);
+        let decoded: OptionValue = toml::from_str("x = \"NaN\"").unwrap();
+        assert!(decoded.x.unwrap().is_nan());
+        let decoded: OptionValue = serde_json::from_str(r#"{"x":0.5}"#).unwrap();
+        assert_eq!(Some(0.5), decoded.x);
+        let decoded: OptionValue = serde_json::from_str("{}").unwrap();
+        assert_eq!(None, decoded.x);
+        assert!(serde_json::from_str::<OptionValue>(r#"{"x":"1.5"}"#).is_err());
+    }
+}
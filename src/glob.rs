@@ -0,0 +1,157 @@
+use anyhow::{bail, Result};
+use regex::Regex;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A glob pattern that matches the trailing components of `/`-separated paths.
+///
+/// `*` matches any characters except `/`, `**` matches any characters, and `**/` any number of
+/// directories, including none. `?` matches any single character except `/`, and `[...]` or
+/// `[!...]` match any character of a set, or any but `/` not of it, where a `]` right after the
+/// opening bracket is part of the set. Matching ignores case, since `.kf` files usually live on
+/// Windows.
+#[derive(Clone, Debug)]
+pub struct Glob {
+    pattern: String,
+    regex: Regex,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self> {
+        // Matching may start after any `/` of the path
+        let mut re = String::from("(?i)(?:^|/)");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        re.push_str("(?:.*/)?");
+                    } else {
+                        re.push_str(".*");
+                    }
+                }
+                '*' => re.push_str("[^/]*"),
+                '?' => re.push_str("[^/]"),
+                '[' => {
+                    re.push('[');
+                    if chars.peek() == Some(&'!') {
+                        chars.next();
+                        re.push_str("^/");
+                    }
+                    for i in 0.. {
+                        match chars.next() {
+                            Some(']') if i > 0 => break,
+                            // Escape what regex classes treat specially, such as `[`, `&&` or `^`
+                            Some(c) if c != '-' && c.is_ascii_punctuation() => {
+                                re.push('\\');
+                                re.push(c);
+                            }
+                            Some(c) => re.push(c),
+                            None => bail!("unclosed `[` in glob `{}`", pattern),
+                        }
+                    }
+                    re.push(']');
+                }
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        re.push('$');
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            regex: Regex::new(&re)?,
+        })
+    }
+
+    /// Returns whether the pattern matches the path, or its trailing components.
+    ///
+    /// Backslashes in the path are treated as `/`.
+    pub fn is_match(&self, path: &str) -> bool {
+        self.regex.is_match(&path.replace("\\", "/"))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+}
+
+impl Serialize for Glob {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.pattern)
+    }
+}
+
+impl<'de> Deserialize<'de> for Glob {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+        Glob::new(&pattern).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Glob;
+    use crate::regex_or::RegexOr;
+
+    #[test]
+    fn test_glob_match() {
+        let glob = Glob::new("*_h_onhit.kf").unwrap();
+        assert!(glob.is_match("./mech/mech_gunbot_h_onhit.kf"));
+        assert!(glob.is_match(".\\mech\\MECH_GUNBOT_H_ONHIT.KF"));
+        assert!(glob.is_match("_h_onhit.kf"));
+        assert!(!glob.is_match("./mech/mech_gunbot_h_onhit.kf.bak"));
+        assert!(!glob.is_match("./mech/mech_gunbot_h_onhitxkf"));
+
+        let glob = Glob::new("mech/*.kf").unwrap();
+        assert!(glob.is_match("./mech/idle.kf"));
+        assert!(!glob.is_match("./mech/sub/idle.kf"));
+        assert!(!glob.is_match("./supermech/idle.kf"));
+        assert!(Glob::new("mech/**.kf")
+            .unwrap()
+            .is_match("./mech/sub/idle.kf"));
+
+        let glob = Glob::new("mech/**/idle.kf").unwrap();
+        assert!(glob.is_match("./mech/idle.kf"));
+        assert!(glob.is_match("./mech/a/b/idle.kf"));
+        assert!(!glob.is_match("./mech/a/b/run_idle.kf"));
+        assert!(!glob.is_match("./supermech/idle.kf"));
+        assert!(Glob::new("**/idle.kf").unwrap().is_match("idle.kf"));
+
+        let glob = Glob::new("idle_[!0-4]?.kf").unwrap();
+        assert!(glob.is_match("idle_5a.kf"));
+        assert!(!glob.is_match("idle_3a.kf"));
+        assert!(!glob.is_match("idle_5/.kf"));
+
+        let glob = Glob::new("idle_[]a].kf").unwrap();
+        assert!(glob.is_match("idle_].kf"));
+        assert!(glob.is_match("idle_A.kf"));
+        assert!(!glob.is_match("idle_b.kf"));
+        let glob = Glob::new("idle_[!]]_[&^[].kf").unwrap();
+        assert!(glob.is_match("idle_b_^.kf"));
+        assert!(!glob.is_match("idle_]_^.kf"));
+        assert!(!glob.is_match("idle_/_[.kf"));
+
+        assert!(Glob::new("idle_[0-4.kf").is_err());
+        assert!(Glob::new("idle_[].kf").is_err());
+    }
+
+    #[test]
+    fn test_glob_de() {
+        let value: RegexOr<Glob> = serde_yaml::from_str("'*.kf'").unwrap();
+        assert!(matches!(value, RegexOr::Other(g) if g.as_str() == "*.kf"));
+
+        let value: RegexOr<Glob> = serde_yaml::from_str("/_h_.*/").unwrap();
+        assert!(matches!(value, RegexOr::Regex(_)));
+
+        let value = RegexOr::Other(Glob::new("*.kf").unwrap());
+        assert_eq!("'*.kf'\n", serde_yaml::to_string(&value).unwrap());
+    }
+}
@@ -0,0 +1,409 @@
+use crate::source::{Animation, SourceFileBody, Transition, TransitionType};
+use crate::text::type_name;
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+
+/// Selects the animations that are at most `depth` transitions away from any of the animations
+/// in `ids`, following transitions in both directions.
+pub fn select_anims(body: &SourceFileBody, ids: &[u32], depth: u32) -> Result<HashSet<u32>> {
+    let anim_ids: HashSet<_> = body.anims.iter().map(|a| a.id).collect();
+    let mut neighbours: HashMap<u32, Vec<u32>> = HashMap::new();
+    for anim in body.anims.iter() {
+        neighbours.entry(anim.id).or_default();
+        // Transitions to missing animations lead nowhere
+        for tran in anim.trans.iter().filter(|t| anim_ids.contains(&t.id)) {
+            neighbours.entry(anim.id).or_default().push(tran.id);
+            neighbours.entry(tran.id).or_default().push(anim.id);
+        }
+    }
+
+    let mut selection = HashSet::new();
+    let mut queue = VecDeque::new();
+    for &id in ids.iter() {
+        if !anim_ids.contains(&id) {
+            bail!("anim {} does not exist", id);
+        }
+        if selection.insert(id) {
+            queue.push_back((id, 0));
+        }
+    }
+
+    while let Some((id, distance)) = queue.pop_front() {
+        if distance == depth {
+            continue;
+        }
+        for &next_id in neighbours[&id].iter() {
+            if selection.insert(next_id) {
+                queue.push_back((next_id, distance + 1));
+            }
+        }
+    }
+
+    Ok(selection)
+}
+
+/// Writes the animations in `selection` and the transitions between them as a Graphviz DOT
+/// digraph.
+///
+/// Animations are labelled with the stem of their `.kf` file and their id, and transitions
+/// with their duration. The chain animations of a transition are drawn as a path of extra
+/// nodes between its source and target animation.
+pub fn write_dot<W>(writer: &mut W, body: &SourceFileBody, selection: &HashSet<u32>) -> Result<()>
+where
+    W: Write,
+{
+    let anims: HashMap<_, _> = body.anims.iter().map(|a| (a.id, a)).collect();
+    let selected = || body.anims.iter().filter(|a| selection.contains(&a.id));
+
+    writeln!(writer, "digraph kfm {{")?;
+    writeln!(writer, "  node [shape=box];")?;
+
+    for anim in selected() {
+        writeln!(
+            writer,
+            "  anim{} [label={}];",
+            anim.id,
+            dot_label(&anim_label(anim))
+        )?;
+    }
+
+    for anim in selected() {
+        for tran in anim.trans.iter().filter(|t| selection.contains(&t.id)) {
+            write_dot_transition(writer, body, &anims, anim.id, tran)?;
+        }
+    }
+
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+/// Writes the animations in `selection` and the transitions between them as a Mermaid
+/// `stateDiagram-v2`.
+///
+/// Transitions are labelled with their type and duration. The transitions of an animation that
+/// transitions to every other drawn animation are collapsed into a single transition to an
+/// `any` state, so that diagrams of fully connected animations stay readable.
+pub fn write_mermaid<W>(
+    writer: &mut W,
+    body: &SourceFileBody,
+    selection: &HashSet<u32>,
+) -> Result<()>
+where
+    W: Write,
+{
+    let selected = || body.anims.iter().filter(|a| selection.contains(&a.id));
+    let num_anims = selected().count();
+
+    writeln!(writer, "stateDiagram-v2")?;
+
+    for anim in selected() {
+        let label = format!("{} ({})", anim.kf_stem(), anim.id);
+        writeln!(
+            writer,
+            "    state \"{}\" as anim{}",
+            label.replace('"', "#quot;"),
+            anim.id
+        )?;
+    }
+
+    let mut has_any_state = false;
+    for anim in selected() {
+        let is_drawn = |id| id != anim.id && selection.contains(&id);
+        let trans: Vec<_> = anim.trans.iter().filter(|t| is_drawn(t.id)).collect();
+        let targets: HashSet<_> = trans.iter().map(|t| t.id).collect();
+        let collapse = num_anims > 2 && targets.len() == num_anims - 1;
+
+        if collapse {
+            if !has_any_state {
+                writeln!(writer, "    state \"any other anim\" as any")?;
+                has_any_state = true;
+            }
+
+            // Keep the label of the transitions if they all share it
+            let labels: HashSet<_> = trans.iter().map(|t| mermaid_label(body, t)).collect();
+            let label = match labels.into_iter().collect::<Vec<_>>().as_slice() {
+                [label] => label.clone(),
+                _ => format!("{} transitions", trans.len()),
+            };
+            writeln!(writer, "    anim{} --> any : {}", anim.id, label)?;
+        }
+
+        // Transitions to the animation itself are never collapsed
+        let drawn = anim
+            .trans
+            .iter()
+            .filter(|t| selection.contains(&t.id) && !(collapse && t.id != anim.id));
+        for tran in drawn {
+            writeln!(
+                writer,
+                "    anim{} --> anim{} : {}",
+                anim.id,
+                tran.id,
+                mermaid_label(body, tran)
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the type and duration of a transition, followed by the ids of its chain animations.
+fn mermaid_label(body: &SourceFileBody, tran: &Transition) -> String {
+    let mut label = type_name(tran.type_).to_string();
+    if let Some(duration) = transition_duration(body, tran) {
+        label.push_str(&format!(" {:?}", duration));
+    }
+
+    let chain_ids: Vec<_> = tran
+        .ext
+        .iter()
+        .flat_map(|ext| ext.chain_anims.iter())
+        .map(|c| c.id.to_string())
+        .collect();
+    if !chain_ids.is_empty() {
+        label.push_str(&format!(" via {}", chain_ids.join(", ")));
+    }
+
+    label
+}
+
+fn write_dot_transition<W>(
+    writer: &mut W,
+    body: &SourceFileBody,
+    anims: &HashMap<u32, &Animation>,
+    anim_id: u32,
+    tran: &Transition,
+) -> Result<()>
+where
+    W: Write,
+{
+    let style = dot_style(tran.type_);
+    let mut from = format!("anim{}", anim_id);
+    let mut duration = transition_duration(body, tran);
+
+    let chain_anims = tran.ext.iter().flat_map(|ext| ext.chain_anims.iter());
+    for (i, chain_anim) in chain_anims.enumerate() {
+        let node = format!("chain{}_{}_{}", anim_id, tran.id, i);
+        let label = match anims.get(&chain_anim.id) {
+            Some(anim) => anim_label(anim),
+            None => chain_anim.id.to_string(),
+        };
+        writeln!(
+            writer,
+            "  {} [label={}, style=dashed];",
+            node,
+            dot_label(&label)
+        )?;
+        write_dot_edge(writer, &from, &node, style, duration)?;
+        from = node;
+        duration = Some(chain_anim.duration);
+    }
+
+    write_dot_edge(writer, &from, &format!("anim{}", tran.id), style, duration)
+}
+
+fn write_dot_edge<W>(
+    writer: &mut W,
+    from: &str,
+    to: &str,
+    style: &str,
+    duration: Option<f32>,
+) -> Result<()>
+where
+    W: Write,
+{
+    write!(writer, "  {} -> {} [{}", from, to, style)?;
+    if let Some(duration) = duration {
+        write!(writer, ", label={}", dot_label(&format!("{:?}", duration)))?;
+    }
+    writeln!(writer, "];")?;
+    Ok(())
+}
+
+/// Edge attributes that tell the types of transitions apart.
+fn dot_style(type_: TransitionType) -> &'static str {
+    match type_ {
+        TransitionType::Blend => "color=black",
+        TransitionType::Morph => "color=blue",
+        TransitionType::Crossfade => "color=darkgreen, style=dashed",
+        TransitionType::ChainAnimation => "color=purple, style=bold",
+        TransitionType::DefaultSync => "color=gray, style=dotted",
+        TransitionType::DefaultNonSync => "color=gray, style=dotted, arrowhead=empty",
+    }
+}
+
+/// Quotes a label as a DOT string, with line breaks between lines.
+fn dot_label(label: &str) -> String {
+    let escaped = label.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{}\"", escaped.replace('\n', "\\n"))
+}
+
+/// Returns the duration of a transition, which default transitions take from the defaults.
+fn transition_duration(body: &SourceFileBody, tran: &Transition) -> Option<f32> {
+    match tran.type_ {
+        TransitionType::DefaultSync => Some(body.default_trans.sync_duration),
+        TransitionType::DefaultNonSync => Some(body.default_trans.non_sync_duration),
+        _ => tran.ext.as_ref().map(|ext| ext.duration),
+    }
+}
+
+/// Returns the stem of an animation's `.kf` file and its id, on separate lines.
+fn anim_label(anim: &Animation) -> String {
+    format!("{}\n{}", anim.kf_stem(), anim.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{select_anims, write_dot, write_mermaid};
+    use crate::source::{Animation, DefaultTransitions, Model, SourceFileBody};
+    use crate::source::{ChainAnimation, Transition, TransitionExt, TransitionType};
+    use indoc::indoc;
+
+    fn make_tran(id: u32, type_: TransitionType, chain_anims: &[u32]) -> Transition {
+        let ext = type_.has_ext().then(|| TransitionExt {
+            duration: 0.5,
+            intermediate_anims: Vec::new(),
+            chain_anims: chain_anims
+                .iter()
+                .map(|&id| ChainAnimation { id, duration: 1.0 })
+                .collect(),
+        });
+        Transition { id, type_, ext }
+    }
+
+    fn make_body() -> SourceFileBody {
+        let make_anim = |id, path: &str, trans| Animation {
+            id,
+            path: path.to_string(),
+            index: 0,
+            trans,
+        };
+
+        SourceFileBody {
+            model: Model {
+                path: "model.nif".to_string(),
+                root: "Root".to_string(),
+            },
+            default_trans: DefaultTransitions {
+                sync_type: TransitionType::Morph,
+                sync_duration: 0.25,
+                non_sync_type: TransitionType::Blend,
+                non_sync_duration: 0.75,
+            },
+            anims: vec![
+                make_anim(
+                    0,
+                    ".\\mech\\idle.kf",
+                    vec![
+                        make_tran(1, TransitionType::DefaultSync, &[]),
+                        make_tran(3, TransitionType::ChainAnimation, &[2]),
+                    ],
+                ),
+                make_anim(1, "run.kf", vec![make_tran(0, TransitionType::Blend, &[])]),
+                make_anim(2, "turn.kf", Vec::new()),
+                make_anim(3, "die.kf", Vec::new()),
+                make_anim(4, "sit.kf", vec![make_tran(3, TransitionType::Morph, &[])]),
+            ],
+            layer_groups: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_select_anims() {
+        let body = make_body();
+        let select = |ids: &[u32], depth| {
+            let mut ids: Vec<_> = select_anims(&body, ids, depth)
+                .unwrap()
+                .into_iter()
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(vec![1], select(&[1], 0));
+        assert_eq!(vec![0, 1], select(&[1], 1));
+        assert_eq!(vec![0, 1, 3], select(&[1], 2));
+        assert_eq!(vec![0, 1, 3, 4], select(&[1], 3));
+        assert_eq!(vec![2, 3, 4], select(&[2, 4], 1));
+        assert!(select_anims(&body, &[7], 0).is_err());
+
+        // Transitions to missing animations are not followed
+        let mut body = make_body();
+        body.anims[2]
+            .trans
+            .push(make_tran(9, TransitionType::Blend, &[]));
+        let selection: Vec<_> = select_anims(&body, &[2], 1).unwrap().into_iter().collect();
+        assert_eq!(vec![2], selection);
+    }
+
+    #[test]
+    fn test_write_dot() {
+        let body = make_body();
+        let selection = select_anims(&body, &[0], 1).unwrap();
+
+        let mut buf = Vec::new();
+        write_dot(&mut buf, &body, &selection).unwrap();
+        let expected = indoc! {r#"
+            digraph kfm {
+              node [shape=box];
+              anim0 [label="idle\n0"];
+              anim1 [label="run\n1"];
+              anim3 [label="die\n3"];
+              anim0 -> anim1 [color=gray, style=dotted, label="0.25"];
+              chain0_3_0 [label="turn\n2", style=dashed];
+              anim0 -> chain0_3_0 [color=purple, style=bold, label="0.5"];
+              chain0_3_0 -> anim3 [color=purple, style=bold, label="1.0"];
+              anim1 -> anim0 [color=black, label="0.5"];
+            }
+        "#};
+        assert_eq!(expected, String::from_utf8(buf).unwrap());
+    }
+
+    #[test]
+    fn test_write_mermaid() {
+        let mut body = make_body();
+        body.anims[0].path = "\"quoted\".kf".to_string();
+        let selection = select_anims(&body, &[0, 1, 3], 0).unwrap();
+
+        let mut buf = Vec::new();
+        write_mermaid(&mut buf, &body, &selection).unwrap();
+        let expected = indoc! {r##"
+            stateDiagram-v2
+                state "#quot;quoted#quot; (0)" as anim0
+                state "run (1)" as anim1
+                state "die (3)" as anim3
+                state "any other anim" as any
+                anim0 --> any : 2 transitions
+                anim1 --> anim0 : blend 0.5
+        "##};
+        assert_eq!(expected, String::from_utf8(buf).unwrap());
+
+        // Transitions that share their label keep it when collapsed
+        body.anims[1]
+            .trans
+            .push(make_tran(3, TransitionType::Blend, &[]));
+        body.anims[1]
+            .trans
+            .push(make_tran(1, TransitionType::Morph, &[]));
+        let mut buf = Vec::new();
+        write_mermaid(&mut buf, &body, &selection).unwrap();
+        let expected = indoc! {r##"
+            stateDiagram-v2
+                state "#quot;quoted#quot; (0)" as anim0
+                state "run (1)" as anim1
+                state "die (3)" as anim3
+                state "any other anim" as any
+                anim0 --> any : 2 transitions
+                anim1 --> any : blend 0.5
+                anim1 --> anim1 : morph 0.5
+        "##};
+        assert_eq!(expected, String::from_utf8(buf).unwrap());
+
+        let chain_tran = &body.anims[0].trans[1];
+        assert_eq!(
+            "chain_animation 0.5 via 2",
+            super::mermaid_label(&body, chain_tran)
+        );
+    }
+}
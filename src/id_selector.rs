@@ -0,0 +1,252 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::de::Error;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_yaml::Value;
+use std::ops::{Bound, RangeBounds};
+use std::str::FromStr;
+
+/// Matches numeric ids of animations, transitions and layers in patches.
+///
+/// Selectors are written as:
+/// - an id, such as `5`;
+/// - a regex, such as `/1\d\d/`, which must match the whole id;
+/// - a range, such as `100..200`, `100..=199`, `..200` or `100..`;
+/// - a comparison, such as `<10`, `<=10`, `>10` or `>=10`;
+/// - a list, such as `[1, 5, 9]`, which matches any of its selectors;
+/// - `not:` and a selector, which matches the ids the selector does not.
+#[derive(Clone, Debug)]
+pub enum IdSelector {
+    Id(u32),
+    /// Holds an anchored regex, as made by [`IdSelector::regex`].
+    Regex(Regex),
+    Range(Bound<u32>, Bound<u32>),
+    List(Vec<IdSelector>),
+    Not(Box<IdSelector>),
+}
+
+impl IdSelector {
+    /// Makes a selector from a regex, which must match whole ids.
+    pub fn regex(pattern: &str) -> Result<Self> {
+        let re = Regex::new(&format!("^(?:{})$", pattern))?;
+        Ok(Self::Regex(re))
+    }
+
+    pub fn is_match(&self, id: u32) -> bool {
+        match self {
+            Self::Id(o) => id == *o,
+            Self::Regex(re) => re.is_match(&id.to_string()),
+            Self::Range(start, end) => (*start, *end).contains(&id),
+            Self::List(list) => list.iter().any(|s| s.is_match(id)),
+            Self::Not(s) => !s.is_match(id),
+        }
+    }
+
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::Number(n) => match n.as_u64().map(u32::try_from) {
+                Some(Ok(id)) => Ok(Self::Id(id)),
+                _ => bail!("invalid id `{}`", n),
+            },
+            Value::String(s) => s.parse(),
+            Value::Sequence(seq) => {
+                let list = seq.into_iter().map(Self::from_value);
+                Ok(Self::List(list.collect::<Result<_>>()?))
+            }
+            Value::Mapping(map) => {
+                let mut iter = map.into_iter();
+                match (iter.next(), iter.next()) {
+                    (Some((Value::String(k), v)), None) if k == "not" => {
+                        Ok(Self::Not(Box::new(Self::from_value(v)?)))
+                    }
+                    _ => bail!("expected a map with only `not`"),
+                }
+            }
+            _ => bail!("expected an id selector"),
+        }
+    }
+}
+
+impl FromStr for IdSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse_id = |id: &str| {
+            id.trim()
+                .parse::<u32>()
+                .with_context(|| format!("invalid id selector `{}`", s))
+        };
+
+        let s = s.trim();
+        if s.len() >= 2 && s.starts_with('/') && s.ends_with('/') {
+            return Self::regex(&s[1..s.len() - 1]);
+        }
+
+        // Two-character operators are checked before their prefixes
+        if let Some(id) = s.strip_prefix("<=") {
+            return Ok(Self::Range(
+                Bound::Unbounded,
+                Bound::Included(parse_id(id)?),
+            ));
+        }
+        if let Some(id) = s.strip_prefix(">=") {
+            return Ok(Self::Range(
+                Bound::Included(parse_id(id)?),
+                Bound::Unbounded,
+            ));
+        }
+        if let Some(id) = s.strip_prefix('<') {
+            return Ok(Self::Range(
+                Bound::Unbounded,
+                Bound::Excluded(parse_id(id)?),
+            ));
+        }
+        if let Some(id) = s.strip_prefix('>') {
+            return Ok(Self::Range(
+                Bound::Excluded(parse_id(id)?),
+                Bound::Unbounded,
+            ));
+        }
+
+        if let Some((start, end)) = s.split_once("..") {
+            let start = match start.trim() {
+                "" => Bound::Unbounded,
+                id => Bound::Included(parse_id(id)?),
+            };
+            let end = match end.trim_start().strip_prefix('=') {
+                Some(id) => Bound::Included(parse_id(id)?),
+                None if end.trim().is_empty() => Bound::Unbounded,
+                None => Bound::Excluded(parse_id(end)?),
+            };
+            return Ok(Self::Range(start, end));
+        }
+
+        Ok(Self::Id(parse_id(s)?))
+    }
+}
+
+impl Serialize for IdSelector {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Self::Id(id) => id.serialize(serializer),
+            Self::Regex(re) => {
+                let pattern = re.as_str();
+                let pattern = pattern
+                    .strip_prefix("^(?:")
+                    .and_then(|p| p.strip_suffix(")$"))
+                    .unwrap_or(pattern);
+                serializer.serialize_str(&format!("/{}/", pattern))
+            }
+            Self::Range(start, end) => {
+                let s = match (start, end) {
+                    (Bound::Unbounded, Bound::Included(e)) => format!("<={}", e),
+                    (Bound::Unbounded, Bound::Excluded(e)) => format!("<{}", e),
+                    (Bound::Excluded(s), Bound::Unbounded) => format!(">{}", s),
+                    (start, end) => {
+                        // Ranges have no excluded start, so it is written as the next id
+                        let start = match start {
+                            Bound::Included(s) => s.to_string(),
+                            Bound::Excluded(s) => s.saturating_add(1).to_string(),
+                            Bound::Unbounded => String::new(),
+                        };
+                        match end {
+                            Bound::Included(e) => format!("{}..={}", start, e),
+                            Bound::Excluded(e) => format!("{}..{}", start, e),
+                            Bound::Unbounded => format!("{}..", start),
+                        }
+                    }
+                };
+                serializer.serialize_str(&s)
+            }
+            Self::List(list) => list.serialize(serializer),
+            Self::Not(s) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("not", s)?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for IdSelector {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = Value::deserialize(deserializer)?;
+        Self::from_value(value).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdSelector;
+
+    #[test]
+    fn test_id_selector_match() {
+        let cases = [
+            ("5", vec![5]),
+            ("/1\\d\\d/", vec![100, 150, 199]),
+            ("100..200", vec![100, 150, 199]),
+            ("100..=199", vec![100, 150, 199]),
+            ("..2", vec![0, 1]),
+            ("1100..", vec![1100, 1500]),
+            ("<2", vec![0, 1]),
+            ("<=1", vec![0, 1]),
+            ("'>1100'", vec![1500]),
+            ("'>=1100'", vec![1100, 1500]),
+            ("[1, 5, 9]", vec![1, 5]),
+            ("[..1, '>1100', /5/]", vec![0, 5, 1500]),
+            ("not: 5", vec![0, 1, 2, 100, 150, 199, 1100, 1500]),
+            ("not: [..2, 100..]", vec![2, 5]),
+        ];
+        let ids = [0, 1, 2, 5, 100, 150, 199, 1100, 1500];
+
+        for (yaml, expected) in cases {
+            let selector: IdSelector = serde_yaml::from_str(yaml).unwrap();
+            let actual: Vec<_> = ids.into_iter().filter(|i| selector.is_match(*i)).collect();
+            assert_eq!(expected, actual, "{}", yaml);
+        }
+    }
+
+    #[test]
+    fn test_id_selector_de_err() {
+        let cases = [
+            ("-1", "invalid id `-1`"),
+            ("'1..x'", "invalid id selector `1..x`"),
+            ("'<'", "invalid id selector `<`"),
+            ("/[/", "regex parse error"),
+            ("{ not: 1, and: 2 }", "expected a map with only `not`"),
+            ("~", "expected an id selector"),
+        ];
+
+        for (yaml, expected) in cases {
+            let err = serde_yaml::from_str::<IdSelector>(yaml).unwrap_err();
+            assert!(err.to_string().starts_with(expected), "{}: {}", yaml, err);
+        }
+    }
+
+    #[test]
+    fn test_id_selector_ser() {
+        let cases = [
+            ("5", "5"),
+            ("/1\\d\\d/", "/1\\d\\d/"),
+            ("' 100 .. 200 '", "100..200"),
+            ("100..=199", "100..=199"),
+            ("..200", "<200"),
+            ("100..", "100.."),
+            ("'>100'", ">100"),
+            ("[1, '<=5', not: 3]", "- 1\n- <=5\n- not: 3"),
+        ];
+
+        for (yaml, expected) in cases {
+            let selector: IdSelector = serde_yaml::from_str(yaml).unwrap();
+            let actual = serde_yaml::to_string(&selector).unwrap();
+            assert_eq!(expected, actual.trim_end().trim_matches('\''), "{}", yaml);
+        }
+    }
+}
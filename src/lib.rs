@@ -0,0 +1,18 @@
+// Lets the derive macros of `kfme-derive` refer to this crate as `::kfme`
+extern crate self as kfme;
+
+pub mod bin;
+pub mod float;
+pub mod glob;
+pub mod graph;
+pub mod header;
+pub mod id_selector;
+pub mod matrix;
+pub mod nif;
+pub mod patch;
+pub mod regex_or;
+pub mod report;
+pub mod roundtrip;
+pub mod source;
+pub mod text;
+pub mod validate;
@@ -1,21 +1,27 @@
-pub mod bin;
-pub mod header;
-pub mod patch;
-pub mod regex_or;
-pub mod source;
-
 use anyhow::{bail, Context, Result};
-use clap::{Parser, Subcommand};
-use header::make_header;
-use patch::PatchFile;
-use source::MappedSource;
-use source::SourceFile;
-use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum};
+use kfme::bin::{Limits, StringEncoding};
+use kfme::graph;
+use kfme::header::make_header;
+use kfme::matrix;
+use kfme::nif::KfFile;
+use kfme::patch::{self, PatchFile};
+use kfme::report::make_report;
+use kfme::roundtrip;
+use kfme::source::{MappedSource, SourceFileBody};
+use kfme::source::{SourceFile, SourceFormat};
+use kfme::validate::{self, Problem, Severity};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Character encoding of strings in binary and text `.kfm` input files
+    #[arg(long, global = true, value_enum, default_value_t = Charset::Utf8)]
+    string_encoding: Charset,
 }
 
 #[derive(Subcommand)]
@@ -27,15 +33,37 @@ enum Commands {
 
         #[arg(long, short)]
         patch: PathBuf,
+
+        /// Checks the patched source file for problems before saving it
+        #[arg(long)]
+        validate: bool,
     },
 
     /// Converts the format of a given source file
     Convert {
+        /// Input file, or `-` to read from stdin
         #[arg(long, short)]
         input: PathBuf,
 
+        /// Output file, or `-` to write to stdout
         #[arg(long, short)]
         output: Option<PathBuf>,
+
+        /// Format of the input, instead of detecting it from its contents
+        #[arg(long)]
+        from: Option<Format>,
+
+        /// Format of the output, instead of inferring it from the file extension
+        #[arg(long)]
+        to: Option<Format>,
+
+        /// Byte order of the output file, if it is a binary file
+        #[arg(long)]
+        endian: Option<Endian>,
+
+        /// Format of the output file, if it is a `.kfm` file
+        #[arg(long)]
+        kfm_format: Option<KfmFormat>,
     },
 
     /// Builds a binary and a corresponding header file from the given source file
@@ -45,20 +73,227 @@ enum Commands {
 
         #[arg(long, short)]
         output_dir: Option<PathBuf>,
+
+        /// Checks the source file for problems before building it
+        #[arg(long)]
+        validate: bool,
+    },
+
+    /// Checks that the given `.kfm` file is written back byte for byte as it was read
+    VerifyRoundtrip { input: PathBuf },
+
+    /// Lists the sequences of the given `.kf` file
+    Sequences { input: PathBuf },
+
+    /// Checks the given source file for problems
+    Validate {
+        input: PathBuf,
+
+        /// Skips the checks against the `.kf` files of the animations
+        #[arg(long)]
+        skip_text_keys: bool,
+    },
+
+    /// Draws the transitions between the animations of the given source file
+    Graph {
+        #[arg(long, short)]
+        input: PathBuf,
+
+        /// Output file; the graph is written to stdout by default
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+
+        /// Comma-separated ids of the animations to draw; all animations are drawn by default
+        #[arg(long, value_delimiter = ',')]
+        ids: Vec<u32>,
+
+        /// Also draws the animations up to this many transitions away from those in `--ids`
+        #[arg(long, default_value_t = 0)]
+        depth: u32,
+    },
+
+    /// Writes an HTML page that describes the given source file, for reviews
+    Report {
+        #[arg(long, short)]
+        input: PathBuf,
+
+        /// Output file; defaults to the input file with the `.html` extension
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        /// Skips the checks against the `.kf` files of the animations
+        #[arg(long)]
+        skip_text_keys: bool,
+    },
+
+    /// Exports or imports the transitions of a source file as a CSV matrix
+    Matrix {
+        #[command(subcommand)]
+        command: MatrixCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum MatrixCommands {
+    /// Writes the transitions of the given source file as a CSV matrix
+    Export {
+        #[arg(long, short)]
+        input: PathBuf,
+
+        /// Output file; the matrix is written to stdout by default
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+
+    /// Applies an edited CSV matrix to the given source file
+    Import {
+        #[arg(long, short)]
+        src: PathBuf,
+
+        #[arg(long, short)]
+        matrix: PathBuf,
+
+        /// Checks the updated source file for problems before saving it
+        #[arg(long)]
+        validate: bool,
     },
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Kfm,
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl From<Format> for SourceFormat {
+    fn from(from: Format) -> Self {
+        match from {
+            Format::Kfm => Self::Kfm,
+            Format::Yaml => Self::Yaml,
+            Format::Json => Self::Json,
+            Format::Toml => Self::Toml,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    /// Mermaid state diagram
+    Mermaid,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Endian {
+    Little,
+    Big,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum KfmFormat {
+    Binary,
+    Text,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Charset {
+    #[value(name = "utf-8")]
+    Utf8,
+    Ascii,
+    #[value(name = "latin-1")]
+    Latin1,
+    #[value(name = "windows-1252")]
+    Windows1252,
+}
+
+impl From<Charset> for StringEncoding {
+    fn from(from: Charset) -> Self {
+        match from {
+            Charset::Utf8 => Self::Utf8,
+            Charset::Ascii => Self::Ascii,
+            Charset::Latin1 => Self::Latin1,
+            Charset::Windows1252 => Self::Windows1252,
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    let string_encoding = cli.string_encoding.into();
     match cli.command {
-        Commands::Patch { src, patch } => on_patch(src, patch),
-        Commands::Convert { input, output } => on_convert(input, output),
-        Commands::Build { input, output_dir } => on_build(input, output_dir),
+        Commands::Patch {
+            src,
+            patch,
+            validate,
+        } => on_patch(src, patch, validate, string_encoding),
+        Commands::Convert {
+            input,
+            output,
+            from,
+            to,
+            endian,
+            kfm_format,
+        } => on_convert(
+            input,
+            output,
+            from.map(Into::into),
+            to.map(Into::into),
+            endian,
+            kfm_format,
+            string_encoding,
+        ),
+        Commands::Build {
+            input,
+            output_dir,
+            validate,
+        } => on_build(input, output_dir, validate, string_encoding),
+        Commands::VerifyRoundtrip { input } => on_verify_roundtrip(input, string_encoding),
+        Commands::Sequences { input } => on_sequences(input),
+        Commands::Validate {
+            input,
+            skip_text_keys,
+        } => on_validate(input, skip_text_keys, string_encoding),
+        Commands::Graph {
+            input,
+            output,
+            format,
+            ids,
+            depth,
+        } => on_graph(input, output, format, ids, depth, string_encoding),
+        Commands::Report {
+            input,
+            output,
+            skip_text_keys,
+        } => on_report(input, output, skip_text_keys, string_encoding),
+        Commands::Matrix {
+            command: MatrixCommands::Export { input, output },
+        } => on_matrix_export(input, output, string_encoding),
+        Commands::Matrix {
+            command:
+                MatrixCommands::Import {
+                    src,
+                    matrix,
+                    validate,
+                },
+        } => on_matrix_import(src, matrix, validate, string_encoding),
     }
 }
 
-fn on_patch(src_path: PathBuf, patch_path: PathBuf) -> Result<()> {
-    let src_file = SourceFile::load(&src_path).context("load source file")?;
+fn on_patch(
+    src_path: PathBuf,
+    patch_path: PathBuf,
+    validate: bool,
+    string_encoding: StringEncoding,
+) -> Result<()> {
+    let (src_file, src_format) =
+        SourceFile::load_detected(&src_path, string_encoding).context("load source file")?;
+    report_detected_format(&src_path, src_format);
     let patch_file = PatchFile::load(patch_path).context("load patch file")?;
 
     // Map source for more efficient edits
@@ -73,44 +308,114 @@ fn on_patch(src_path: PathBuf, patch_path: PathBuf) -> Result<()> {
         body: m_src.into(),
     };
 
-    // Save source file
-    new_src_file.save(src_path).context("save source file")?;
+    if validate {
+        report_problems(&validate::check_integrity(&new_src_file.body))
+            .context("validate patched source file")?;
+    }
+
+    // Save source file in the format it was read in
+    new_src_file
+        .save_as(src_path, src_format)
+        .context("save source file")?;
 
     Ok(())
 }
 
-fn on_convert(input_path: PathBuf, maybe_output_path: Option<PathBuf>) -> Result<()> {
-    let input_file = SourceFile::load(&input_path).context("load input file")?;
-    let output_file = input_file;
+fn on_convert(
+    input_path: PathBuf,
+    maybe_output_path: Option<PathBuf>,
+    maybe_input_format: Option<SourceFormat>,
+    maybe_output_format: Option<SourceFormat>,
+    maybe_endian: Option<Endian>,
+    maybe_kfm_format: Option<KfmFormat>,
+    string_encoding: StringEncoding,
+) -> Result<()> {
+    let (input_file, input_format) = if is_stdio(&input_path) {
+        let reader = io::stdin().lock();
+        match maybe_input_format {
+            Some(f) => SourceFile::from_reader_with(reader, f, Limits::default(), string_encoding)
+                .map(|src_file| (src_file, f)),
+            None => SourceFile::from_reader_detected(reader, Limits::default(), string_encoding)
+                .inspect(|(_, f)| report_detected_format(&input_path, *f)),
+        }
+    } else {
+        match maybe_input_format {
+            Some(f) => {
+                SourceFile::load_as(&input_path, f, string_encoding).map(|src_file| (src_file, f))
+            }
+            None => SourceFile::load_detected(&input_path, string_encoding)
+                .inspect(|(_, f)| report_detected_format(&input_path, *f)),
+        }
+    }
+    .context("load input file")?;
+    let mut output_file = input_file;
+
+    // Re-encode in the requested byte order, if any. It is checked here, since the output
+    // file would be created before encoding fails.
+    if let Some(endian) = maybe_endian {
+        output_file.header.is_little_endian = matches!(endian, Endian::Little);
+        output_file
+            .header
+            .check_endianness()
+            .context("check byte order")?;
+    }
+
+    // Switch between binary and text `.kfm` output, if requested
+    if let Some(kfm_format) = maybe_kfm_format {
+        output_file.header.is_text = matches!(kfm_format, KfmFormat::Text);
+    }
 
     // Determine the output file path.
     // If a path is provided, use it; otherwise, derive it from the input file path.
     let output_file_path = match maybe_output_path {
         Some(p) => p,
+        None if is_stdio(&input_path) => bail!("`--output` is required when reading from stdin"),
         None => {
             let mut p = input_path.clone();
-            let p_old_ext = p
-                .extension()
-                .and_then(|s| s.to_str())
-                .context("extension unreadable")?;
-            let p_new_ext = match p_old_ext {
-                "yaml" | "yml" => "kfm",
-                "kfm" => "yaml",
-                _ => bail!("unsupported extension"),
+            let p_new_format = match maybe_output_format {
+                Some(f) => f,
+                None if input_format == SourceFormat::Kfm => SourceFormat::Yaml,
+                None => SourceFormat::Kfm,
             };
-            p.set_extension(p_new_ext);
+            p.set_extension(p_new_format.extension());
             p
         }
     };
 
+    let output_format = match maybe_output_format {
+        Some(f) => f,
+        None if is_stdio(&output_file_path) => bail!("`--to` is required when writing to stdout"),
+        None => SourceFormat::from_path(&output_file_path).context("output format")?,
+    };
+
     // Save source file
-    output_file
-        .save(output_file_path)
-        .context("save output file")
+    if is_stdio(&output_file_path) {
+        let mut stdout = io::stdout().lock();
+        output_file
+            .to_writer(&mut stdout, output_format)
+            .context("write output")?;
+        stdout.flush().context("write output")
+    } else {
+        output_file
+            .save_as(output_file_path, output_format)
+            .context("save output file")
+    }
 }
 
-fn on_build(input_path: PathBuf, maybe_output_dir_path: Option<PathBuf>) -> Result<()> {
-    let input_file = SourceFile::load(&input_path).context("load input file")?;
+fn on_build(
+    input_path: PathBuf,
+    maybe_output_dir_path: Option<PathBuf>,
+    validate: bool,
+    string_encoding: StringEncoding,
+) -> Result<()> {
+    let (input_file, input_format) =
+        SourceFile::load_detected(&input_path, string_encoding).context("load input file")?;
+    report_detected_format(&input_path, input_format);
+
+    if validate {
+        report_problems(&validate::check_integrity(&input_file.body))
+            .context("validate input file")?;
+    }
 
     let output_src_file_stem = input_path
         .file_stem()
@@ -135,8 +440,9 @@ fn on_build(input_path: PathBuf, maybe_output_dir_path: Option<PathBuf>) -> Resu
             .to_path_buf(),
     };
 
-    // Make binary source file.
-    let output_src_file = input_file;
+    // Make binary source file, since the engine cannot load text `.kfm` files.
+    let mut output_src_file = input_file;
+    output_src_file.header.is_text = false;
 
     // Make binary source file path.
     let mut output_src_path = output_dir_path.join(&output_src_file_stem);
@@ -161,3 +467,241 @@ fn on_build(input_path: PathBuf, maybe_output_dir_path: Option<PathBuf>) -> Resu
 
     Ok(())
 }
+
+fn on_verify_roundtrip(input_path: PathBuf, string_encoding: StringEncoding) -> Result<()> {
+    let input_bytes = std::fs::read(&input_path).context("read input file")?;
+
+    match roundtrip::verify(&input_bytes, string_encoding).context("verify round trip")? {
+        Some(mismatch) => bail!("{}", mismatch),
+        None => println!("{:?}: round trip is byte-exact", input_path),
+    }
+
+    Ok(())
+}
+
+fn on_sequences(input_path: PathBuf) -> Result<()> {
+    let kf_file = KfFile::load(&input_path).context("load input file")?;
+
+    for (i, seq) in kf_file.sequences.iter().enumerate() {
+        println!(
+            "{}: {:?} ({} to {}, {})",
+            i, seq.name, seq.start_time, seq.stop_time, seq.cycle_type
+        );
+        for key in seq.text_keys.iter() {
+            println!("    {}: {:?}", key.time, key.value);
+        }
+    }
+
+    Ok(())
+}
+
+fn on_validate(
+    input_path: PathBuf,
+    skip_text_keys: bool,
+    string_encoding: StringEncoding,
+) -> Result<()> {
+    // Transitions with a bad `ext` are reported with all other problems
+    let (input_file, input_format) =
+        SourceFile::load_detected_unchecked(&input_path, string_encoding)
+            .context("load input file")?;
+    report_detected_format(&input_path, input_format);
+
+    report_problems(&find_problems(
+        &input_path,
+        &input_file.body,
+        skip_text_keys,
+    ))
+}
+
+/// Runs all checks on a source file, optionally skipping those that need its `.kf` files.
+fn find_problems(input_path: &Path, body: &SourceFileBody, skip_text_keys: bool) -> Vec<Problem> {
+    let mut problems = validate::check_integrity(body);
+
+    if !skip_text_keys {
+        // Paths of `.kf` files are relative to the source file
+        let input_dir = input_path.parent().unwrap_or(Path::new(""));
+        problems.extend(validate::check_text_keys(body, |anim_path| {
+            KfFile::load(validate::resolve_kf_path(input_dir, anim_path))
+        }));
+    }
+
+    problems
+}
+
+fn on_report(
+    input_path: PathBuf,
+    maybe_output_path: Option<PathBuf>,
+    skip_text_keys: bool,
+    string_encoding: StringEncoding,
+) -> Result<()> {
+    let (input_file, input_format) =
+        SourceFile::load_detected_unchecked(&input_path, string_encoding)
+            .context("load input file")?;
+    report_detected_format(&input_path, input_format);
+
+    let problems = find_problems(&input_path, &input_file.body, skip_text_keys);
+
+    let title = input_path
+        .file_name()
+        .context("file name")?
+        .to_string_lossy()
+        .to_string();
+    let report = make_report(&title, &input_file.body, &problems)?;
+
+    let output_path = maybe_output_path.unwrap_or_else(|| input_path.with_extension("html"));
+    std::fs::write(output_path, report).context("write output file")
+}
+
+fn on_graph(
+    input_path: PathBuf,
+    maybe_output_path: Option<PathBuf>,
+    format: GraphFormat,
+    ids: Vec<u32>,
+    depth: u32,
+    string_encoding: StringEncoding,
+) -> Result<()> {
+    let (input_file, input_format) =
+        SourceFile::load_detected(&input_path, string_encoding).context("load input file")?;
+    report_detected_format(&input_path, input_format);
+    let body = &input_file.body;
+
+    let selection = if ids.is_empty() {
+        if depth > 0 {
+            bail!("`--depth` requires `--ids`");
+        }
+        body.anims.iter().map(|a| a.id).collect()
+    } else {
+        graph::select_anims(body, &ids, depth)?
+    };
+
+    let mut buf = Vec::new();
+    match format {
+        GraphFormat::Dot => graph::write_dot(&mut buf, body, &selection)?,
+        GraphFormat::Mermaid => graph::write_mermaid(&mut buf, body, &selection)?,
+    }
+
+    match maybe_output_path {
+        Some(p) if !is_stdio(&p) => std::fs::write(p, buf).context("write output file"),
+        _ => io::stdout().lock().write_all(&buf).context("write output"),
+    }
+}
+
+fn on_matrix_export(
+    input_path: PathBuf,
+    maybe_output_path: Option<PathBuf>,
+    string_encoding: StringEncoding,
+) -> Result<()> {
+    let (input_file, input_format) =
+        SourceFile::load_detected(&input_path, string_encoding).context("load input file")?;
+    report_detected_format(&input_path, input_format);
+
+    let mut buf = Vec::new();
+    matrix::write_matrix(&mut buf, &input_file.body)?;
+
+    match maybe_output_path {
+        Some(p) if !is_stdio(&p) => std::fs::write(p, buf).context("write output file"),
+        _ => io::stdout().lock().write_all(&buf).context("write output"),
+    }
+}
+
+fn on_matrix_import(
+    src_path: PathBuf,
+    matrix_path: PathBuf,
+    validate: bool,
+    string_encoding: StringEncoding,
+) -> Result<()> {
+    let (src_file, src_format) =
+        SourceFile::load_detected(&src_path, string_encoding).context("load source file")?;
+    report_detected_format(&src_path, src_format);
+    let matrix_file = std::fs::File::open(matrix_path).context("open matrix file")?;
+
+    let mut m_src = MappedSource::try_from(src_file.body)?;
+    let changes = matrix::apply_matrix(matrix_file, &mut m_src).context("apply matrix")?;
+    for change in changes.iter() {
+        println!("{}", change);
+    }
+
+    let new_src_file = SourceFile {
+        header: src_file.header,
+        body: m_src.into(),
+    };
+
+    if validate {
+        report_problems(&validate::check_integrity(&new_src_file.body))
+            .context("validate updated source file")?;
+    }
+
+    // Save source file in the format it was read in
+    new_src_file
+        .save_as(src_path, src_format)
+        .context("save source file")?;
+
+    Ok(())
+}
+
+/// Reports problems found in a source file, and fails if any of them is an error.
+fn report_problems(problems: &[Problem]) -> Result<()> {
+    for problem in problems.iter() {
+        eprintln!("{}", problem);
+    }
+
+    let num_errors = problems
+        .iter()
+        .filter(|p| p.severity == Severity::Error)
+        .count();
+    if num_errors > 0 {
+        bail!(
+            "found {} errors and {} warnings",
+            num_errors,
+            problems.len() - num_errors
+        );
+    }
+
+    Ok(())
+}
+
+/// Reports the detected format of an input file, or of stdin, on stderr.
+fn report_detected_format(path: &Path, format: SourceFormat) {
+    if is_stdio(path) {
+        eprintln!("stdin: detected {} format", format);
+    } else {
+        eprintln!("{:?}: detected {} format", path, format);
+    }
+}
+
+/// Returns whether the path stands for stdin or stdout.
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::on_build;
+    use kfme::bin::StringEncoding;
+    use kfme::source::SourceFile;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_build_text_kfm() {
+        let output_dir = std::env::temp_dir().join(format!("kfme-build-{}", std::process::id()));
+        fs::create_dir_all(&output_dir).unwrap();
+
+        let input_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v2_2_0_0a.kfm");
+        let result = on_build(
+            input_path,
+            Some(output_dir.clone()),
+            false,
+            StringEncoding::default(),
+        );
+        let output = fs::read(output_dir.join("v2_2_0_0a.kfm"));
+        fs::remove_dir_all(&output_dir).unwrap();
+        result.unwrap();
+
+        // The text input is built into a binary file
+        let output = output.unwrap();
+        assert!(output[1..].starts_with(b"Gamebryo KFM File Version 2.2.0.0b\n"));
+        let src_file = SourceFile::from_kfm_reader(&output[..]).unwrap();
+        assert!(!src_file.header.is_text);
+    }
+}
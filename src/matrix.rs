@@ -0,0 +1,410 @@
+use crate::source::{MappedSource, MappedTransition, SourceFileBody};
+use crate::source::{TransitionExt, TransitionType};
+use crate::text::{parse_type_name, type_name};
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io::{Read, Write};
+
+/// A transition that was added, changed or removed by `apply_matrix`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub anim_id: u32,
+    pub trans_id: u32,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "anim {}, trans {}: ", self.anim_id, self.trans_id)?;
+        match (&self.old, &self.new) {
+            (None, Some(new)) => write!(f, "added `{}`", new),
+            (Some(old), Some(new)) => write!(f, "changed `{}` to `{}`", old, new),
+            (Some(old), None) => write!(f, "removed `{}`", old),
+            (None, None) => write!(f, "unchanged"),
+        }
+    }
+}
+
+/// Writes the transitions of a source file as an N×N CSV matrix.
+///
+/// Rows are source animations and columns are target animations, both headed by the stem of
+/// the animation's `.kf` file and its id, as in `idle (0)`. Each cell holds the type and
+/// duration of the transition, as in `morph 0.25`, or nothing if there is no transition.
+pub fn write_matrix<W>(writer: W, body: &SourceFileBody) -> Result<()>
+where
+    W: Write,
+{
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    for record in make_matrix(body) {
+        csv_writer.write_record(&record)?;
+    }
+
+    csv_writer.flush()?;
+    Ok(())
+}
+
+/// Returns the rows of the matrix written by `write_matrix`, starting with the header row.
+pub(crate) fn make_matrix(body: &SourceFileBody) -> Vec<Vec<String>> {
+    let headers: Vec<_> = body
+        .anims
+        .iter()
+        .map(|a| format!("{} ({})", a.kf_stem(), a.id))
+        .collect();
+
+    let mut rows = vec![std::iter::once(String::new())
+        .chain(headers.iter().cloned())
+        .collect()];
+    for (anim, header) in body.anims.iter().zip(headers.iter()) {
+        let mut row = vec![header.clone()];
+        for target in body.anims.iter() {
+            let cell = anim
+                .trans
+                .iter()
+                .find(|t| t.id == target.id)
+                .map(|t| format_cell(t.type_, t.ext.as_ref()))
+                .unwrap_or_default();
+            row.push(cell);
+        }
+        rows.push(row);
+    }
+
+    rows
+}
+
+/// Applies a CSV matrix written by `write_matrix` to the transitions of a source file.
+///
+/// Only the animations that head a row or a column are touched, so rows and columns can be
+/// removed from the matrix to leave their transitions alone. Empty cells remove transitions.
+/// Changed cells keep the intermediate animations of the transition they replace, and its chain
+/// animations if it stays a `chain_animation`. Nothing is applied if any row or cell is invalid.
+///
+/// Returns the changes made, in the order of the cells.
+pub fn apply_matrix<R>(reader: R, m_src: &mut MappedSource) -> Result<Vec<Change>>
+where
+    R: Read,
+{
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader);
+    let mut records = csv_reader.records();
+
+    let header_record = records.next().context("missing header row")??;
+    let target_ids = header_record
+        .iter()
+        .skip(1)
+        .map(|h| parse_header(h, m_src))
+        .collect::<Result<Vec<_>>>()
+        .context("read header row")?;
+    check_unique(&target_ids).context("read header row")?;
+
+    let mut rows = Vec::new();
+    for (i, record) in records.enumerate() {
+        let row_num = i + 2;
+        let record = record.with_context(|| format!("read row {}", row_num))?;
+
+        let anim_id = parse_header(record.get(0).unwrap_or_default(), m_src)
+            .with_context(|| format!("read row {}", row_num))?;
+        if record.len() != target_ids.len() + 1 {
+            bail!(
+                "row {} has {} cells, expected {}",
+                row_num,
+                record.len(),
+                target_ids.len() + 1
+            );
+        }
+        rows.push((anim_id, record));
+    }
+    let anim_ids: Vec<_> = rows.iter().map(|(anim_id, _)| *anim_id).collect();
+    check_unique(&anim_ids)?;
+
+    // Parse every cell before applying any, so that errors leave the source unchanged
+    let mut cells = Vec::new();
+    for (anim_id, record) in rows.iter() {
+        let trans = &m_src.anims[anim_id].trans;
+        for (&trans_id, cell) in target_ids.iter().zip(record.iter().skip(1)) {
+            let new = parse_cell(cell, trans.get(&trans_id))
+                .with_context(|| format!("read cell of anim {} and trans {}", anim_id, trans_id))?;
+            cells.push((*anim_id, trans_id, new));
+        }
+    }
+
+    let mut changes = Vec::new();
+    for (anim_id, trans_id, new) in cells {
+        let anim = m_src.anims.get_mut(&anim_id).unwrap();
+        changes.extend(apply_cell(anim_id, &mut anim.trans, trans_id, new));
+    }
+
+    Ok(changes)
+}
+
+/// Applies a parsed cell to the transitions of an animation, and returns the change if the
+/// cell differs from the transition.
+fn apply_cell(
+    anim_id: u32,
+    trans: &mut BTreeMap<u32, MappedTransition>,
+    trans_id: u32,
+    new: Option<MappedTransition>,
+) -> Option<Change> {
+    let old = trans
+        .get(&trans_id)
+        .map(|t| format_cell(t.type_, t.ext.as_ref()));
+    let new_cell = new.as_ref().map(|t| format_cell(t.type_, t.ext.as_ref()));
+    if old == new_cell {
+        return None;
+    }
+
+    match new {
+        Some(new) => trans.insert(trans_id, new),
+        None => trans.remove(&trans_id),
+    };
+    Some(Change {
+        anim_id,
+        trans_id,
+        old,
+        new: new_cell,
+    })
+}
+
+/// Parses a cell into a transition, taking the intermediate animations from `old`, and its
+/// chain animations if both are a `chain_animation`.
+fn parse_cell(cell: &str, old: Option<&MappedTransition>) -> Result<Option<MappedTransition>> {
+    let mut tokens = cell.split_whitespace();
+    let Some(name) = tokens.next() else {
+        return Ok(None);
+    };
+    let type_ = parse_type_name(name).with_context(|| format!("unknown trans type: `{}`", name))?;
+
+    let duration = match tokens.next() {
+        Some(s) => Some(
+            s.parse::<f32>()
+                .with_context(|| format!("invalid duration: `{}`", s))?,
+        ),
+        None => None,
+    };
+    if tokens.next().is_some() {
+        bail!("too many values in `{}`", cell);
+    }
+
+    let ext = match (type_.has_ext(), duration) {
+        (true, Some(duration)) => {
+            let old_ext = old.and_then(|t| t.ext.as_ref());
+            Some(TransitionExt {
+                duration,
+                intermediate_anims: old_ext
+                    .map(|e| e.intermediate_anims.clone())
+                    .unwrap_or_default(),
+                chain_anims: old_ext
+                    .filter(|_| type_ == TransitionType::ChainAnimation)
+                    .map(|e| e.chain_anims.clone())
+                    .unwrap_or_default(),
+            })
+        }
+        (true, None) => bail!("type `{}` requires a duration", name),
+        (false, Some(_)) => bail!("type `{}` takes its duration from the defaults", name),
+        (false, None) => None,
+    };
+
+    Ok(Some(MappedTransition { type_, ext }))
+}
+
+fn format_cell(type_: TransitionType, ext: Option<&TransitionExt>) -> String {
+    match ext {
+        Some(ext) => format!("{} {:?}", type_name(type_), ext.duration),
+        None => type_name(type_).to_string(),
+    }
+}
+
+/// Parses the id of an animation from a header like `idle (0)`, or a bare id.
+fn parse_header(header: &str, m_src: &MappedSource) -> Result<u32> {
+    let header = header.trim();
+    let id_str = match header.strip_suffix(')').and_then(|h| h.rsplit_once('(')) {
+        Some((_, id_str)) => id_str,
+        None => header,
+    };
+    let id = id_str
+        .parse()
+        .with_context(|| format!("invalid anim header: `{}`", header))?;
+
+    if !m_src.anims.contains_key(&id) {
+        bail!("anim {} does not exist", id);
+    }
+    Ok(id)
+}
+
+fn check_unique(ids: &[u32]) -> Result<()> {
+    let mut seen = HashSet::new();
+    for id in ids.iter() {
+        if !seen.insert(id) {
+            bail!("duplicate anim {}", id);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_matrix, write_matrix, Change};
+    use crate::source::{Animation, DefaultTransitions, MappedSource, Model, SourceFileBody};
+    use crate::source::{ChainAnimation, Transition, TransitionExt, TransitionType};
+    use indoc::indoc;
+
+    fn make_tran(id: u32, type_: TransitionType, duration: f32) -> Transition {
+        let ext = type_.has_ext().then(|| TransitionExt {
+            duration,
+            intermediate_anims: Vec::new(),
+            chain_anims: Vec::new(),
+        });
+        Transition { id, type_, ext }
+    }
+
+    fn make_body() -> SourceFileBody {
+        let make_anim = |id, path: &str, trans| Animation {
+            id,
+            path: path.to_string(),
+            index: 0,
+            trans,
+        };
+
+        let mut chain = make_tran(0, TransitionType::ChainAnimation, 0.5);
+        chain.ext.as_mut().unwrap().chain_anims = vec![ChainAnimation {
+            id: 2,
+            duration: 1.0,
+        }];
+
+        SourceFileBody {
+            model: Model {
+                path: "model.nif".to_string(),
+                root: "Root".to_string(),
+            },
+            default_trans: DefaultTransitions {
+                sync_type: TransitionType::Morph,
+                sync_duration: 0.25,
+                non_sync_type: TransitionType::Blend,
+                non_sync_duration: 0.25,
+            },
+            anims: vec![
+                make_anim(
+                    0,
+                    ".\\mech\\idle.kf",
+                    vec![
+                        make_tran(1, TransitionType::Morph, 0.25),
+                        make_tran(2, TransitionType::DefaultSync, 0.0),
+                    ],
+                ),
+                make_anim(1, "run, fast.kf", vec![chain]),
+                make_anim(2, "turn.kf", Vec::new()),
+            ],
+            layer_groups: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_write_matrix() {
+        let mut buf = Vec::new();
+        write_matrix(&mut buf, &make_body()).unwrap();
+        let expected = indoc! {r#"
+            ,idle (0),"run, fast (1)",turn (2)
+            idle (0),,morph 0.25,default_sync
+            "run, fast (1)",chain_animation 0.5,,
+            turn (2),,,
+        "#};
+        assert_eq!(expected, String::from_utf8(buf).unwrap());
+    }
+
+    #[test]
+    fn test_apply_matrix() {
+        let mut m_src = MappedSource::try_from(make_body()).unwrap();
+        let csv = indoc! {"
+            ,0,idle (1),2
+            renamed (1),blend 0.5,,crossfade 0.1
+            2,,default_non_sync,
+            idle (0), ,morph 0.250,default_sync
+        "};
+
+        let changes = apply_matrix(csv.as_bytes(), &mut m_src).unwrap();
+        let change = |anim_id, trans_id, old: Option<&str>, new: Option<&str>| Change {
+            anim_id,
+            trans_id,
+            old: old.map(str::to_string),
+            new: new.map(str::to_string),
+        };
+        let expected = vec![
+            change(1, 0, Some("chain_animation 0.5"), Some("blend 0.5")),
+            change(1, 2, None, Some("crossfade 0.1")),
+            change(2, 1, None, Some("default_non_sync")),
+        ];
+        assert_eq!(expected, changes);
+        assert_eq!(
+            "anim 1, trans 0: changed `chain_animation 0.5` to `blend 0.5`",
+            changes[0].to_string()
+        );
+
+        // Chain animations are dropped when the type changes
+        let tran = &m_src.anims[&1].trans[&0];
+        assert!(tran.ext.as_ref().unwrap().chain_anims.is_empty());
+
+        let csv = ",0\n1,\n";
+        let changes = apply_matrix(csv.as_bytes(), &mut m_src).unwrap();
+        assert_eq!(vec![change(1, 0, Some("blend 0.5"), None)], changes);
+        assert!(!m_src.anims[&1].trans.contains_key(&0));
+    }
+
+    #[test]
+    fn test_apply_matrix_chain_anims() {
+        let mut m_src = MappedSource::try_from(make_body()).unwrap();
+        let csv = ",0\n1,chain_animation 0.75\n";
+        apply_matrix(csv.as_bytes(), &mut m_src).unwrap();
+
+        // Chain animations are kept when only the duration changes
+        let ext = m_src.anims[&1].trans[&0].ext.as_ref().unwrap();
+        assert_eq!(0.75, ext.duration);
+        assert_eq!(2, ext.chain_anims[0].id);
+    }
+
+    #[test]
+    fn test_apply_matrix_errors() {
+        let apply = |csv: &str| {
+            let mut m_src = MappedSource::try_from(make_body()).unwrap();
+            format!(
+                "{:#}",
+                apply_matrix(csv.as_bytes(), &mut m_src).unwrap_err()
+            )
+        };
+
+        assert_eq!("read header row: anim 7 does not exist", apply(",0,7\n"));
+        assert_eq!("read header row: duplicate anim 0", apply(",0,idle (0)\n"));
+        assert_eq!(
+            "read row 2: invalid anim header: `idle`: invalid digit found in string",
+            apply(",0\nidle,\n")
+        );
+        assert_eq!("row 2 has 3 cells, expected 2", apply(",0\n1,,\n"));
+        assert_eq!(
+            "read cell of anim 1 and trans 0: type `morph` requires a duration",
+            apply(",0\n1,morph\n")
+        );
+        assert_eq!(
+            "read cell of anim 1 and trans 0: type `default_sync` takes its duration from the \
+             defaults",
+            apply(",0\n1,default_sync 0.5\n")
+        );
+        assert_eq!("duplicate anim 1", apply(",0\n1,\n1,\n"));
+    }
+
+    #[test]
+    fn test_apply_matrix_error_leaves_source() {
+        let mut m_src = MappedSource::try_from(make_body()).unwrap();
+        let before = format!("{:?}", m_src.anims);
+
+        // A duplicate row, and an invalid cell after a valid one
+        for csv in [
+            ",0,2\n1,,crossfade 0.1\n1,,\n",
+            ",0,2\n1,,crossfade 0.1\n0,morph,\n",
+        ] {
+            assert!(apply_matrix(csv.as_bytes(), &mut m_src).is_err());
+            assert_eq!(before, format!("{:?}", m_src.anims), "{}", csv);
+        }
+    }
+}
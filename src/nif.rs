@@ -0,0 +1,635 @@
+use crate::bin::{Decode, Limits, Options, ReadValueExt, TrackingReader};
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// First version that stores the size of each block, which lets unknown blocks be skipped.
+const MIN_VERSION: NifVersion = NifVersion::new(20, 2, 0, 5);
+
+/// First version that stores hashes instead of the names of block types.
+const HASHED_BLOCK_TYPES_VERSION: NifVersion = NifVersion::new(20, 3, 1, 2);
+
+const HEADER_STRING_PREFIXES: [&str; 2] = [
+    "Gamebryo File Format, Version ",
+    "NetImmerse File Format, Version ",
+];
+
+const MAX_HEADER_STRING_LEN: usize = 128;
+
+/// The sequences of a Gamebryo `.kf` file.
+///
+/// Only the `NiControllerSequence` blocks and the text keys they refer to are read. All other
+/// blocks are skipped, which requires a file version that stores block sizes, i.e. `20.2.0.5`
+/// or later.
+#[derive(Debug, Clone)]
+pub struct KfFile {
+    pub version: NifVersion,
+    pub sequences: Vec<ControllerSequence>,
+}
+
+impl KfFile {
+    /// Loads a `KfFile` from a given file path.
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::open(path).context("open file")?;
+        let file_len = file.metadata().context("read file metadata")?.len();
+
+        // The whole file is the remaining input
+        let default_limits = Limits::default();
+        let limits = Limits {
+            max_total_bytes: file_len.min(default_limits.max_total_bytes),
+            ..default_limits
+        };
+
+        Self::from_reader_with(BufReader::new(file), limits)
+    }
+
+    /// Creates a `KfFile` from a reader.
+    pub fn from_reader<R>(reader: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        Self::from_reader_with(reader, Limits::default())
+    }
+
+    /// Creates a `KfFile` from a reader, enforcing the given decode limits.
+    pub fn from_reader_with<R>(reader: R, limits: Limits) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut reader = TrackingReader::new(reader, limits.max_total_bytes);
+        let opts = Options {
+            limits,
+            ..Default::default()
+        };
+
+        reader.read_field_with("header_string", |r| {
+            let mut buf = Vec::new();
+            loop {
+                let b = r.read_u8()?;
+                if b == b'\n' {
+                    break;
+                }
+                buf.push(b);
+                if buf.len() >= MAX_HEADER_STRING_LEN {
+                    bail!("unterminated `header_string`");
+                }
+            }
+
+            let header_string = String::from_utf8_lossy(&buf);
+            if !HEADER_STRING_PREFIXES
+                .iter()
+                .any(|p| header_string.starts_with(p))
+            {
+                bail!("not a NIF file: `{}`", header_string);
+            }
+            Ok(())
+        })?;
+
+        let version = NifVersion(reader.read_field::<u32, LittleEndian>("version", &opts)?);
+        if version < MIN_VERSION || version >= HASHED_BLOCK_TYPES_VERSION {
+            bail!("unsupported NIF version `{}`", version);
+        }
+
+        let is_little_endian = reader.read_field_with("endian_type", |r| match r.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => bail!("unknown `endian_type`: {}", b),
+        })?;
+
+        let sequences = if is_little_endian {
+            read_sequences::<_, LittleEndian>(&mut reader, version, &opts)?
+        } else {
+            read_sequences::<_, BigEndian>(&mut reader, version, &opts)?
+        };
+
+        Ok(Self { version, sequences })
+    }
+}
+
+/// Version of a NIF file, with one byte for each of its four components.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy)]
+pub struct NifVersion(pub u32);
+
+impl NifVersion {
+    pub const fn new(major: u8, minor: u8, patch: u8, build: u8) -> Self {
+        Self(u32::from_be_bytes([major, minor, patch, build]))
+    }
+}
+
+impl fmt::Display for NifVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [major, minor, patch, build] = self.0.to_be_bytes();
+        write!(f, "{}.{}.{}.{}", major, minor, patch, build)
+    }
+}
+
+/// An animation sequence, as stored in a `NiControllerSequence` block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerSequence {
+    pub name: String,
+    pub start_time: f32,
+    pub stop_time: f32,
+    pub cycle_type: CycleType,
+    pub text_keys: Vec<TextKey>,
+}
+
+impl ControllerSequence {
+    /// Returns whether the sequence has a text key with the given value.
+    pub fn has_text_key(&self, value: &str) -> bool {
+        self.text_keys.iter().any(|k| k.value == value)
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum CycleType {
+    Loop,
+    Reverse,
+    Clamp,
+}
+
+impl fmt::Display for CycleType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Loop => "loop",
+            Self::Reverse => "reverse",
+            Self::Clamp => "clamp",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextKey {
+    pub time: f32,
+    pub value: String,
+}
+
+/// The parts of the file header that are needed to read the blocks.
+struct Header {
+    /// Version of the Bethesda header extension, or 0 if there is none.
+    bs_version: u32,
+    block_types: Vec<String>,
+    block_type_indices: Vec<u16>,
+    block_sizes: Vec<u32>,
+    strings: Vec<String>,
+}
+
+impl Header {
+    /// Returns the string at `index` of the string table, or an empty string for index -1.
+    fn string(&self, index: i32) -> Result<String> {
+        if index == -1 {
+            return Ok(String::new());
+        }
+
+        match usize::try_from(index)
+            .ok()
+            .and_then(|i| self.strings.get(i))
+        {
+            Some(s) => Ok(s.clone()),
+            None => bail!("string index {} out of range", index),
+        }
+    }
+}
+
+/// A `NiControllerSequence` block whose references are not resolved yet.
+struct RawSequence {
+    name: i32,
+    text_keys: i32,
+    cycle_type: CycleType,
+    start_time: f32,
+    stop_time: f32,
+}
+
+#[derive(Decode)]
+struct RawTextKey {
+    time: f32,
+    value: i32,
+}
+
+fn read_sequences<R, O>(
+    reader: &mut TrackingReader<R>,
+    version: NifVersion,
+    opts: &Options,
+) -> Result<Vec<ControllerSequence>>
+where
+    R: Read,
+    O: ByteOrder,
+{
+    let header = read_header::<_, O>(reader, version, opts)?;
+
+    let mut raw_sequences = Vec::new();
+    let mut text_key_blocks = HashMap::new();
+
+    let blocks = header.block_type_indices.iter().zip(&header.block_sizes);
+    for (i, (type_index, size)) in blocks.enumerate() {
+        // The high bit flags blocks that are specific to PhysX
+        let type_index = (type_index & 0x7fff) as usize;
+        let block_type = match header.block_types.get(type_index) {
+            Some(t) => t.as_str(),
+            None => bail!("block {} has unknown type index {}", i, type_index),
+        };
+
+        let start = reader.position();
+        let size = *size as u64;
+        if size > reader.remaining() {
+            bail!(
+                "block {} size {} exceeds remaining input of {} bytes",
+                i,
+                size,
+                reader.remaining()
+            );
+        }
+
+        match block_type {
+            "NiControllerSequence" => {
+                let seq = reader
+                    .read_field_with("sequence", |r| read_sequence::<_, O>(r, &header, opts))
+                    .with_context(|| format!("read block {}", i))?;
+                raw_sequences.push(seq);
+            }
+            "NiTextKeyExtraData" => {
+                let _name = reader.read_field::<i32, O>("name", opts)?;
+                let keys = reader
+                    .read_field::<Vec<RawTextKey>, O>("text_keys", opts)
+                    .with_context(|| format!("read block {}", i))?;
+                text_key_blocks.insert(i, keys);
+            }
+            _ => {}
+        }
+
+        // Skip the rest of the block
+        let consumed = reader.position() - start;
+        if consumed > size {
+            bail!("block {} exceeds its size of {} bytes", i, size);
+        }
+        skip(reader, size - consumed).with_context(|| format!("skip block {}", i))?;
+    }
+
+    let mut sequences = Vec::with_capacity(raw_sequences.len());
+    for raw in raw_sequences {
+        let name = header.string(raw.name).context("read sequence name")?;
+
+        let text_keys = if raw.text_keys == -1 {
+            Vec::new()
+        } else {
+            let raw_keys = usize::try_from(raw.text_keys)
+                .ok()
+                .and_then(|i| text_key_blocks.get(&i));
+            let Some(raw_keys) = raw_keys else {
+                bail!(
+                    "text keys of sequence `{}` refer to block {}, which holds no text keys",
+                    name,
+                    raw.text_keys
+                );
+            };
+
+            raw_keys
+                .iter()
+                .map(|k| {
+                    Ok(TextKey {
+                        time: k.time,
+                        value: header.string(k.value)?,
+                    })
+                })
+                .collect::<Result<_>>()
+                .with_context(|| format!("read text keys of sequence `{}`", name))?
+        };
+
+        sequences.push(ControllerSequence {
+            name,
+            start_time: raw.start_time,
+            stop_time: raw.stop_time,
+            cycle_type: raw.cycle_type,
+            text_keys,
+        });
+    }
+
+    Ok(sequences)
+}
+
+fn read_header<R, O>(
+    reader: &mut TrackingReader<R>,
+    version: NifVersion,
+    opts: &Options,
+) -> Result<Header>
+where
+    R: Read,
+    O: ByteOrder,
+{
+    let user_version = reader.read_field::<u32, O>("user_version", opts)?;
+    let num_blocks = reader.read_field::<u32, O>("num_blocks", opts)? as usize;
+
+    let bs_version = if version == NifVersion::new(20, 2, 0, 7) && user_version >= 3 {
+        reader.read_field_with("bs_header", |r| read_bs_header::<_, O>(r, opts))?
+    } else {
+        0
+    };
+
+    let num_block_types = reader.read_field::<u16, O>("num_block_types", opts)? as usize;
+    let block_types = read_array::<_, String, O>(reader, "block_types", num_block_types, opts)?;
+    let block_type_indices =
+        read_array::<_, u16, O>(reader, "block_type_indices", num_blocks, opts)?;
+    let block_sizes = read_array::<_, u32, O>(reader, "block_sizes", num_blocks, opts)?;
+
+    let num_strings = reader.read_field::<u32, O>("num_strings", opts)? as usize;
+    let _max_string_len = reader.read_field::<u32, O>("max_string_len", opts)?;
+    let strings = read_array::<_, String, O>(reader, "strings", num_strings, opts)?;
+
+    let _groups = reader.read_field::<Vec<u32>, O>("groups", opts)?;
+
+    Ok(Header {
+        bs_version,
+        block_types,
+        block_type_indices,
+        block_sizes,
+        strings,
+    })
+}
+
+/// Reads the header extension of files exported for Bethesda games, and returns its version.
+fn read_bs_header<R, O>(reader: &mut TrackingReader<R>, opts: &Options) -> Result<u32>
+where
+    R: Read,
+    O: ByteOrder,
+{
+    let bs_version = reader.read_field::<u32, O>("bs_version", opts)?;
+
+    skip_export_string(reader, "author")?;
+    if bs_version > 130 {
+        let _unknown = reader.read_field::<u32, O>("unknown", opts)?;
+    }
+    if bs_version < 131 {
+        skip_export_string(reader, "process_script")?;
+    }
+    skip_export_string(reader, "export_script")?;
+    if bs_version >= 103 {
+        skip_export_string(reader, "max_filepath")?;
+    }
+
+    Ok(bs_version)
+}
+
+fn read_sequence<R, O>(
+    reader: &mut TrackingReader<R>,
+    header: &Header,
+    opts: &Options,
+) -> Result<RawSequence>
+where
+    R: Read,
+    O: ByteOrder,
+{
+    let name = reader.read_field::<i32, O>("name", opts)?;
+    let num_controlled_blocks = reader.read_field::<u32, O>("num_controlled_blocks", opts)?;
+    let _array_grow_by = reader.read_field::<u32, O>("array_grow_by", opts)?;
+
+    // Controlled blocks are not needed. Each has two references and five strings, and files
+    // for Bethesda games add a priority byte.
+    let controlled_block_len = if header.bs_version > 0 { 29 } else { 28 };
+    reader.read_field_with("controlled_blocks", |r| {
+        skip(r, num_controlled_blocks as u64 * controlled_block_len)
+    })?;
+
+    let _weight = reader.read_field::<f32, O>("weight", opts)?;
+    let text_keys = reader.read_field::<i32, O>("text_keys", opts)?;
+    let cycle_type =
+        reader.read_field_with("cycle_type", |r| match r.read_value::<u32, O>(opts)? {
+            0 => Ok(CycleType::Loop),
+            1 => Ok(CycleType::Reverse),
+            2 => Ok(CycleType::Clamp),
+            c => bail!("unknown `cycle_type`: {}", c),
+        })?;
+    let _frequency = reader.read_field::<f32, O>("frequency", opts)?;
+    let start_time = reader.read_field::<f32, O>("start_time", opts)?;
+    let stop_time = reader.read_field::<f32, O>("stop_time", opts)?;
+
+    Ok(RawSequence {
+        name,
+        text_keys,
+        cycle_type,
+        start_time,
+        stop_time,
+    })
+}
+
+/// Reads `len` items into the field `name`, checking `len` against the decode limits.
+fn read_array<R, T, O>(
+    reader: &mut TrackingReader<R>,
+    name: &'static str,
+    len: usize,
+    opts: &Options,
+) -> Result<Vec<T>>
+where
+    R: Read,
+    T: Decode,
+    O: ByteOrder,
+{
+    reader.read_field_with(name, |r| {
+        if len > opts.limits.max_items {
+            bail!(
+                "item count {} exceeds limit of {}",
+                len,
+                opts.limits.max_items
+            );
+        }
+        if len as u64 > r.remaining() {
+            bail!(
+                "item count {} exceeds remaining input of {} bytes",
+                len,
+                r.remaining()
+            );
+        }
+
+        (0..len).map(|i| r.read_item::<T, O>(i, opts)).collect()
+    })
+}
+
+/// Skips a string with a single byte length prefix.
+fn skip_export_string<R>(reader: &mut TrackingReader<R>, name: &'static str) -> Result<()>
+where
+    R: Read,
+{
+    reader.read_field_with(name, |r| {
+        let len = r.read_u8()?;
+        skip(r, len as u64)
+    })
+}
+
+fn skip<R>(reader: &mut R, len: u64) -> Result<()>
+where
+    R: ReadValueExt,
+{
+    if len > reader.remaining() {
+        bail!(
+            "length {} exceeds remaining input of {} bytes",
+            len,
+            reader.remaining()
+        );
+    }
+
+    let skipped = io::copy(&mut reader.by_ref().take(len), &mut io::sink())?;
+    if skipped < len {
+        bail!("unexpected end of file");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{CycleType, KfFile, NifVersion, TextKey};
+    use crate::bin::Limits;
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use std::io::Write;
+
+    /// Makes a `.kf` file of version `20.2.0.7` with the given sequences, each given by its
+    /// name and text keys.
+    ///
+    /// Each sequence is followed by its text keys and by a block of a type that is not read.
+    pub(crate) fn make_kf(sequences: &[(&str, &[&str])]) -> Vec<u8> {
+        let mut strings: Vec<String> = Vec::new();
+        let mut string_index = |s: &str| match strings.iter().position(|t| t == s) {
+            Some(i) => i as i32,
+            None => {
+                strings.push(s.to_string());
+                strings.len() as i32 - 1
+            }
+        };
+
+        let mut block_type_indices = Vec::new();
+        let mut blocks = Vec::new();
+        for (name, keys) in sequences {
+            let seq_index = blocks.len() as i32;
+
+            let mut seq = Vec::new();
+            seq.write_i32::<LittleEndian>(string_index(name)).unwrap();
+            seq.write_u32::<LittleEndian>(1).unwrap(); // num_controlled_blocks
+            seq.write_u32::<LittleEndian>(1).unwrap(); // array_grow_by
+            seq.write_all(&[0xff; 28]).unwrap(); // controlled_blocks
+            seq.write_f32::<LittleEndian>(1.0).unwrap(); // weight
+            seq.write_i32::<LittleEndian>(seq_index + 1).unwrap(); // text_keys
+            seq.write_u32::<LittleEndian>(2).unwrap(); // cycle_type
+            seq.write_f32::<LittleEndian>(1.0).unwrap(); // frequency
+            seq.write_f32::<LittleEndian>(0.0).unwrap(); // start_time
+            seq.write_f32::<LittleEndian>(keys.len() as f32).unwrap(); // stop_time
+            seq.write_all(&[0xff; 8]).unwrap(); // fields that are not read
+            block_type_indices.push(0u16);
+            blocks.push(seq);
+
+            let mut extra = Vec::new();
+            extra.write_i32::<LittleEndian>(-1).unwrap(); // name
+            extra.write_u32::<LittleEndian>(keys.len() as u32).unwrap();
+            for (i, key) in keys.iter().enumerate() {
+                extra.write_f32::<LittleEndian>(i as f32).unwrap();
+                extra.write_i32::<LittleEndian>(string_index(key)).unwrap();
+            }
+            block_type_indices.push(1);
+            blocks.push(extra);
+
+            block_type_indices.push(2);
+            blocks.push(vec![0xff; 10]);
+        }
+
+        let block_types = [
+            "NiControllerSequence",
+            "NiTextKeyExtraData",
+            "NiTransformInterpolator",
+        ];
+
+        let mut buf = Vec::new();
+        buf.write_all(b"Gamebryo File Format, Version 20.2.0.7\n")
+            .unwrap();
+        buf.write_u32::<LittleEndian>(NifVersion::new(20, 2, 0, 7).0)
+            .unwrap();
+        buf.write_u8(1).unwrap(); // endian_type
+        buf.write_u32::<LittleEndian>(0).unwrap(); // user_version
+        buf.write_u32::<LittleEndian>(blocks.len() as u32).unwrap();
+        buf.write_u16::<LittleEndian>(block_types.len() as u16)
+            .unwrap();
+        for t in block_types {
+            buf.write_u32::<LittleEndian>(t.len() as u32).unwrap();
+            buf.write_all(t.as_bytes()).unwrap();
+        }
+        for i in block_type_indices {
+            buf.write_u16::<LittleEndian>(i).unwrap();
+        }
+        for b in blocks.iter() {
+            buf.write_u32::<LittleEndian>(b.len() as u32).unwrap();
+        }
+        buf.write_u32::<LittleEndian>(strings.len() as u32).unwrap();
+        let max_string_len = strings.iter().map(|s| s.len()).max().unwrap_or(0);
+        buf.write_u32::<LittleEndian>(max_string_len as u32)
+            .unwrap();
+        for s in strings.iter() {
+            buf.write_u32::<LittleEndian>(s.len() as u32).unwrap();
+            buf.write_all(s.as_bytes()).unwrap();
+        }
+        buf.write_u32::<LittleEndian>(0).unwrap(); // num_groups
+        for b in blocks {
+            buf.write_all(&b).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn test_read_sequences() {
+        let bytes = make_kf(&[("idle", &["start", "end"]), ("run", &[]), ("hit", &["end"])]);
+        let kf = KfFile::from_reader(&bytes[..]).unwrap();
+
+        assert_eq!(NifVersion::new(20, 2, 0, 7), kf.version);
+        assert_eq!("20.2.0.7", kf.version.to_string());
+
+        let names: Vec<_> = kf.sequences.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(vec!["idle", "run", "hit"], names);
+
+        let idle = &kf.sequences[0];
+        assert_eq!(CycleType::Clamp, idle.cycle_type);
+        assert_eq!(0.0, idle.start_time);
+        assert_eq!(2.0, idle.stop_time);
+        assert_eq!(
+            vec![
+                TextKey {
+                    time: 0.0,
+                    value: "start".to_string()
+                },
+                TextKey {
+                    time: 1.0,
+                    value: "end".to_string()
+                },
+            ],
+            idle.text_keys
+        );
+        assert!(kf.sequences[1].text_keys.is_empty());
+        assert!(kf.sequences[2].has_text_key("end"));
+        assert!(!kf.sequences[2].has_text_key("start"));
+    }
+
+    #[test]
+    fn test_read_errors() {
+        assert!(KfFile::from_reader(&b";Gamebryo KFM File Version 2.2.0.0b\n"[..]).is_err());
+
+        // Versions without block sizes
+        let mut bytes = make_kf(&[("idle", &[])]);
+        let version_offset = "Gamebryo File Format, Version 20.2.0.7\n".len();
+        bytes[version_offset..version_offset + 4]
+            .copy_from_slice(&NifVersion::new(20, 0, 0, 5).0.to_le_bytes());
+        let err = KfFile::from_reader(&bytes[..]).unwrap_err();
+        assert!(err.to_string().contains("20.0.0.5"), "{}", err);
+
+        // Truncated files
+        let bytes = make_kf(&[("idle", &["start"])]);
+        for len in 0..bytes.len() {
+            let limits = Limits {
+                max_total_bytes: len as u64,
+                ..Limits::default()
+            };
+            assert!(KfFile::from_reader_with(&bytes[..len], limits).is_err());
+        }
+    }
+}
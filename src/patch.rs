@@ -1,8 +1,13 @@
+use crate::glob::Glob;
+use crate::id_selector::IdSelector;
 use crate::regex_or::RegexOr;
-use crate::source::{Animation, TransitionExt, TransitionType};
-use crate::source::{MappedSource, MappedTransition};
+use crate::source::{Animation, Layer, LayerGroup, TransitionExt, TransitionType};
+use crate::source::{ChainAnimation, DefaultTransitions, IntermediateAnimation};
+use crate::source::{MappedAnimation, MappedSource, MappedTransition};
 use anyhow::{bail, Context, Result};
-use serde::{Deserialize, Serialize};
+use regex::RegexBuilder;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::{BufReader, Read};
@@ -10,21 +15,39 @@ use std::path::Path;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PatchFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<UpdateModel>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_trans: Option<UpdateDefaultTransitions>,
+
+    #[serde(default)]
     pub anims: Vec<AnimationPatch>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layer_groups: Vec<LayerGroupPatch>,
 }
 
 impl PatchFile {
     /// Loads a `PatchFile` from a given file path.
+    ///
+    /// Files with a `.json` or `.toml` extension are read as JSON or TOML, all others as YAML.
     pub fn load<P>(path: P) -> Result<Self>
     where
         P: AsRef<Path>,
     {
+        let path = path.as_ref();
         let file = File::open(path).context("open file")?;
         let reader = BufReader::new(file);
-        Self::from_reader(reader)
+
+        match path.extension().and_then(|s| s.to_str()) {
+            Some("json") => Self::from_json_reader(reader),
+            Some("toml") => Self::from_toml_reader(reader),
+            _ => Self::from_reader(reader),
+        }
     }
 
-    /// Creates a `PatchFile` from a reader.
+    /// Creates a `PatchFile` from a YAML format reader.
     pub fn from_reader<R>(reader: R) -> Result<Self>
     where
         R: Read,
@@ -32,6 +55,60 @@ impl PatchFile {
         let f: Self = serde_yaml::from_reader(reader)?;
         Ok(f)
     }
+
+    /// Creates a `PatchFile` from a JSON format reader.
+    pub fn from_json_reader<R>(reader: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let f: Self = serde_json::from_reader(reader)?;
+        Ok(f)
+    }
+
+    /// Creates a `PatchFile` from a TOML format reader.
+    pub fn from_toml_reader<R>(mut reader: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut s = String::new();
+        reader.read_to_string(&mut s)?;
+        let f: Self = toml::from_str(&s)?;
+        Ok(f)
+    }
+}
+
+/// An instruction to update the model of a source file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateModel {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+}
+
+/// An instruction to update the default transitions of a source file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateDefaultTransitions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync_type: Option<TransitionType>,
+
+    #[serde(
+        default,
+        deserialize_with = "crate::float::deserialize_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub sync_duration: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub non_sync_type: Option<TransitionType>,
+
+    #[serde(
+        default,
+        deserialize_with = "crate::float::deserialize_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub non_sync_duration: Option<f32>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -77,14 +154,70 @@ impl From<UpdateAnimation> for AnimationPatchBody {
 
 pub type AddAnimation = Animation;
 
+/// Matches animations by their path and index, on top of their id.
+///
+/// `path` is a glob, such as `*_h_onhit.kf`, or a regex. Both ignore case. Every given field
+/// must match.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnimationSelector {
+    #[serde(
+        default,
+        deserialize_with = "deserialize_path",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub path: Option<RegexOr<Glob>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<IdSelector>,
+}
+
+impl AnimationSelector {
+    fn is_match(&self, anim: &MappedAnimation) -> bool {
+        let path_matches = match &self.path {
+            Some(RegexOr::Regex(re)) => re.is_match(&anim.path),
+            Some(RegexOr::Other(glob)) => glob.is_match(&anim.path),
+            None => true,
+        };
+        let index_matches = match &self.index {
+            Some(index) => index.is_match(anim.index),
+            None => true,
+        };
+
+        path_matches && index_matches
+    }
+}
+
+/// Deserializes the path of an `AnimationSelector`, making its regex ignore case like globs do.
+fn deserialize_path<'de, D>(deserializer: D) -> Result<Option<RegexOr<Glob>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<RegexOr<Glob>>::deserialize(deserializer)? {
+        Some(RegexOr::Regex(re)) => RegexBuilder::new(re.as_str())
+            .case_insensitive(true)
+            .build()
+            .map(|re| Some(RegexOr::Regex(re)))
+            .map_err(D::Error::custom),
+        path => Ok(path),
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DeleteAnimation {
-    pub id: RegexOr<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<IdSelector>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select: Option<AnimationSelector>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UpdateAnimation {
-    pub id: RegexOr<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<IdSelector>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select: Option<AnimationSelector>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub path: Option<String>,
@@ -140,7 +273,11 @@ impl From<UpdateTransition> for TransitionPatchBody {
 /// An instruction to add a transition to an animation.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AddTransition {
-    pub id: RegexOr<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<IdSelector>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select: Option<AnimationSelector>,
 
     #[serde(rename = "type")]
     pub type_: TransitionType,
@@ -152,22 +289,313 @@ pub struct AddTransition {
 /// An instruction to delete an existing transition of an animation.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DeleteTransition {
-    pub id: RegexOr<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<IdSelector>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select: Option<AnimationSelector>,
 }
 
 /// An instruction to update the data an existing transition of an animation.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UpdateTransition {
-    pub id: RegexOr<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<IdSelector>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select: Option<AnimationSelector>,
 
     #[serde(rename = "type")]
     pub type_: Option<TransitionType>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub ext: Option<TransitionExt>,
+    pub ext: Option<UpdateTransitionExt>,
+}
+
+/// An instruction to update the ext of existing transitions, keeping the fields it does not
+/// mention.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateTransitionExt {
+    #[serde(
+        default,
+        deserialize_with = "crate::float::deserialize_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duration: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub intermediate_anims: Option<Vec<IntermediateAnimationPatch>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_anims: Option<Vec<ChainAnimationPatch>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IntermediateAnimationPatch {
+    #[serde(flatten)]
+    pub body: IntermediateAnimationPatchBody,
+}
+
+impl<T> From<T> for IntermediateAnimationPatch
+where
+    T: Into<IntermediateAnimationPatchBody>,
+{
+    fn from(from: T) -> Self {
+        Self { body: from.into() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum IntermediateAnimationPatchBody {
+    Add(AddIntermediateAnimation),
+    Delete(DeleteIntermediateAnimation),
+}
+
+impl From<AddIntermediateAnimation> for IntermediateAnimationPatchBody {
+    fn from(from: AddIntermediateAnimation) -> Self {
+        Self::Add(from)
+    }
+}
+
+impl From<DeleteIntermediateAnimation> for IntermediateAnimationPatchBody {
+    fn from(from: DeleteIntermediateAnimation) -> Self {
+        Self::Delete(from)
+    }
+}
+
+/// An instruction to add a pair of keys to the intermediate animations of a transition.
+pub type AddIntermediateAnimation = IntermediateAnimation;
+
+/// An instruction to delete the intermediate animations of a transition whose keys match.
+///
+/// Keys that are not given match any key, so `delete: {}` deletes all of them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeleteIntermediateAnimation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_key: Option<RegexOr<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_key: Option<RegexOr<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChainAnimationPatch {
+    #[serde(flatten)]
+    pub body: ChainAnimationPatchBody,
+}
+
+impl<T> From<T> for ChainAnimationPatch
+where
+    T: Into<ChainAnimationPatchBody>,
+{
+    fn from(from: T) -> Self {
+        Self { body: from.into() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ChainAnimationPatchBody {
+    Add(AddChainAnimation),
+    Delete(DeleteChainAnimation),
+    Update(UpdateChainAnimation),
+}
+
+impl From<AddChainAnimation> for ChainAnimationPatchBody {
+    fn from(from: AddChainAnimation) -> Self {
+        Self::Add(from)
+    }
+}
+
+impl From<DeleteChainAnimation> for ChainAnimationPatchBody {
+    fn from(from: DeleteChainAnimation) -> Self {
+        Self::Delete(from)
+    }
+}
+
+impl From<UpdateChainAnimation> for ChainAnimationPatchBody {
+    fn from(from: UpdateChainAnimation) -> Self {
+        Self::Update(from)
+    }
+}
+
+/// An instruction to append an animation to the chain animations of a transition.
+pub type AddChainAnimation = ChainAnimation;
+
+/// An instruction to delete chain animations of a transition.
+///
+/// All of them are deleted if no id is given, as in `delete: {}`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeleteChainAnimation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<IdSelector>,
+}
+
+/// An instruction to update the data of chain animations of a transition.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateChainAnimation {
+    pub id: IdSelector,
+
+    #[serde(
+        default,
+        deserialize_with = "crate::float::deserialize_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duration: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LayerGroupPatch {
+    #[serde(flatten)]
+    pub body: LayerGroupPatchBody,
+}
+
+impl<T> From<T> for LayerGroupPatch
+where
+    T: Into<LayerGroupPatchBody>,
+{
+    fn from(from: T) -> Self {
+        Self { body: from.into() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerGroupPatchBody {
+    Add(AddLayerGroup),
+    Delete(DeleteLayerGroup),
+    Update(UpdateLayerGroup),
+}
+
+impl From<AddLayerGroup> for LayerGroupPatchBody {
+    fn from(from: AddLayerGroup) -> Self {
+        Self::Add(from)
+    }
+}
+
+impl From<DeleteLayerGroup> for LayerGroupPatchBody {
+    fn from(from: DeleteLayerGroup) -> Self {
+        Self::Delete(from)
+    }
+}
+
+impl From<UpdateLayerGroup> for LayerGroupPatchBody {
+    fn from(from: UpdateLayerGroup) -> Self {
+        Self::Update(from)
+    }
+}
+
+pub type AddLayerGroup = LayerGroup;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeleteLayerGroup {
+    pub id: IdSelector,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateLayerGroup {
+    pub id: IdSelector,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layers: Option<Vec<LayerPatch>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LayerPatch {
+    #[serde(flatten)]
+    pub body: LayerPatchBody,
+}
+
+impl<T> From<T> for LayerPatch
+where
+    T: Into<LayerPatchBody>,
+{
+    fn from(from: T) -> Self {
+        Self { body: from.into() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerPatchBody {
+    Add(AddLayer),
+    Delete(DeleteLayer),
+    Update(UpdateLayer),
+}
+
+impl From<AddLayer> for LayerPatchBody {
+    fn from(from: AddLayer) -> Self {
+        Self::Add(from)
+    }
+}
+
+impl From<DeleteLayer> for LayerPatchBody {
+    fn from(from: DeleteLayer) -> Self {
+        Self::Delete(from)
+    }
+}
+
+impl From<UpdateLayer> for LayerPatchBody {
+    fn from(from: UpdateLayer) -> Self {
+        Self::Update(from)
+    }
+}
+
+/// An instruction to add a layer to a layer group.
+pub type AddLayer = Layer;
+
+/// An instruction to delete existing layers of a layer group.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeleteLayer {
+    pub id: IdSelector,
+}
+
+/// An instruction to update the data of existing layers of a layer group.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateLayer {
+    pub id: IdSelector,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+
+    #[serde(
+        default,
+        deserialize_with = "crate::float::deserialize_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub weight: Option<f32>,
+
+    #[serde(
+        default,
+        deserialize_with = "crate::float::deserialize_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ease_in_time: Option<f32>,
+
+    #[serde(
+        default,
+        deserialize_with = "crate::float::deserialize_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ease_out_time: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync_id: Option<u32>,
 }
 
 pub fn apply(m_src: &mut MappedSource, patch_file: PatchFile) -> Result<()> {
+    if let Some(update) = patch_file.model {
+        on_update_model(m_src, update);
+    }
+
+    if let Some(update) = patch_file.default_trans {
+        on_update_default_trans(m_src, update).context("update default trans")?;
+    }
+
     for anim_patch in patch_file.anims.into_iter() {
         match anim_patch.body {
             AnimationPatchBody::Add(a) => on_add_anim(m_src, a)?,
@@ -176,6 +604,63 @@ pub fn apply(m_src: &mut MappedSource, patch_file: PatchFile) -> Result<()> {
         }
     }
 
+    for layer_group_patch in patch_file.layer_groups.into_iter() {
+        match layer_group_patch.body {
+            LayerGroupPatchBody::Add(a) => on_add_layer_group(m_src, a)?,
+            LayerGroupPatchBody::Delete(d) => on_delete_layer_group(m_src, d)?,
+            LayerGroupPatchBody::Update(u) => on_update_layer_group(m_src, u)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn on_update_model(m_src: &mut MappedSource, update: UpdateModel) {
+    if let Some(path) = update.path {
+        m_src.model.path = path;
+    }
+    if let Some(root) = update.root {
+        m_src.model.root = root;
+    }
+}
+
+fn on_update_default_trans(
+    m_src: &mut MappedSource,
+    update: UpdateDefaultTransitions,
+) -> Result<()> {
+    let default_trans = &mut m_src.default_trans;
+
+    // Check every field before applying any
+    for (name, type_) in [
+        ("sync_type", update.sync_type),
+        ("non_sync_type", update.non_sync_type),
+    ] {
+        if let Some(type_) = type_ {
+            DefaultTransitions::check_type(name, type_)?;
+        }
+    }
+    for (name, duration) in [
+        ("sync_duration", update.sync_duration),
+        ("non_sync_duration", update.non_sync_duration),
+    ] {
+        if let Some(duration) = duration {
+            DefaultTransitions::check_duration(name, duration)?;
+        }
+    }
+
+    if let Some(sync_type) = update.sync_type {
+        default_trans.sync_type = sync_type;
+    }
+    if let Some(sync_duration) = update.sync_duration {
+        default_trans.sync_duration = sync_duration;
+    }
+    if let Some(non_sync_type) = update.non_sync_type {
+        default_trans.non_sync_type = non_sync_type;
+    }
+    if let Some(non_sync_duration) = update.non_sync_duration {
+        default_trans.non_sync_duration = non_sync_duration;
+    }
+
     Ok(())
 }
 
@@ -193,7 +678,7 @@ fn on_add_anim(m_src: &mut MappedSource, add: AddAnimation) -> Result<()> {
 
 fn on_delete_anim(m_src: &mut MappedSource, delete: DeleteAnimation) -> Result<()> {
     let all_ids = m_src.anims.keys().cloned();
-    let delete_ids: HashSet<_> = collect_matching_ids(all_ids, delete.id);
+    let delete_ids = select_anim_ids(m_src, all_ids, &delete.id, &delete.select)?;
 
     // Delete matching animations
     m_src.anims.retain(|id, _| !delete_ids.contains(id));
@@ -208,7 +693,7 @@ fn on_delete_anim(m_src: &mut MappedSource, delete: DeleteAnimation) -> Result<(
 
 fn on_update_anim(m_src: &mut MappedSource, update: UpdateAnimation) -> Result<()> {
     let all_ids = m_src.anims.keys().cloned();
-    let update_ids: HashSet<_> = collect_matching_ids(all_ids, update.id);
+    let update_ids = select_anim_ids(m_src, all_ids, &update.id, &update.select)?;
 
     for update_id in update_ids.iter() {
         let anim = match m_src.anims.get_mut(update_id) {
@@ -244,7 +729,7 @@ fn on_update_anim(m_src: &mut MappedSource, update: UpdateAnimation) -> Result<(
 fn on_add_tran(m_src: &mut MappedSource, parent_anim_id: u32, add: &AddTransition) -> Result<()> {
     // Find all transition ids to add to the parent animation
     let all_anim_ids = m_src.anims.keys().cloned();
-    let mut add_tran_ids: HashSet<_> = collect_matching_ids(all_anim_ids, add.id.clone());
+    let mut add_tran_ids = select_anim_ids(m_src, all_anim_ids, &add.id, &add.select)?;
     add_tran_ids.remove(&parent_anim_id);
 
     let parent_anim = match m_src.anims.get_mut(&parent_anim_id) {
@@ -257,6 +742,9 @@ fn on_add_tran(m_src: &mut MappedSource, parent_anim_id: u32, add: &AddTransitio
             type_: add.type_,
             ext: add.ext.clone(),
         };
+        tran.type_
+            .check_ext(tran.ext.as_ref())
+            .with_context(|| format!("add tran `{}` to anim `{}`", tran_id, parent_anim_id))?;
 
         // Add transition to parent animation
         let old = parent_anim.trans.insert(tran_id, tran);
@@ -277,14 +765,17 @@ fn on_delete_tran(
     parent_anim_id: u32,
     delete: &DeleteTransition,
 ) -> Result<()> {
-    let parent_anim = match m_src.anims.get_mut(&parent_anim_id) {
+    let parent_anim = match m_src.anims.get(&parent_anim_id) {
         Some(a) => a,
         None => bail!("get parent anim `{}`", parent_anim_id),
     };
 
     // Find all transition ids to remove from the parent animation
     let all_tran_ids = parent_anim.trans.keys().cloned();
-    let delete_tran_ids: HashSet<_> = collect_matching_ids(all_tran_ids, delete.id.clone());
+    let delete_tran_ids = select_anim_ids(m_src, all_tran_ids, &delete.id, &delete.select)?;
+
+    // Unwrapping is fine, since the parent animation was found above
+    let parent_anim = m_src.anims.get_mut(&parent_anim_id).unwrap();
 
     for tran_id in delete_tran_ids.into_iter() {
         // Remove transition from parent animation
@@ -306,14 +797,17 @@ fn on_update_tran(
     parent_anim_id: u32,
     update: &UpdateTransition,
 ) -> Result<()> {
-    let parent_anim = match m_src.anims.get_mut(&parent_anim_id) {
+    let parent_anim = match m_src.anims.get(&parent_anim_id) {
         Some(a) => a,
         None => bail!("get parent anim `{}`", parent_anim_id),
     };
 
     // Find all transition ids to update from the parent animation
     let all_tran_ids = parent_anim.trans.keys().cloned();
-    let update_tran_ids: HashSet<_> = collect_matching_ids(all_tran_ids, update.id.clone());
+    let update_tran_ids = select_anim_ids(m_src, all_tran_ids, &update.id, &update.select)?;
+
+    // Unwrapping is fine, since the parent animation was found above
+    let parent_anim = m_src.anims.get_mut(&parent_anim_id).unwrap();
 
     for tran_id in update_tran_ids.into_iter() {
         let tran = match parent_anim.trans.get_mut(&tran_id) {
@@ -323,25 +817,288 @@ fn on_update_tran(
 
         // Update transition type of parent animation
         if let Some(type_) = update.type_ {
+            // Chain animations are only played by chain animation transitions
+            let is_chain = |t| t == TransitionType::ChainAnimation;
+            if is_chain(tran.type_) && !is_chain(type_) {
+                if let Some(ext) = &mut tran.ext {
+                    ext.chain_anims.clear();
+                }
+            }
             tran.type_ = type_;
         }
 
         // Update transition ext of parent animation
-        tran.ext = update.ext.clone();
+        on_update_tran_ext(tran, update.ext.as_ref())
+            .with_context(|| format!("update tran `{}` of anim `{}`", tran_id, parent_anim_id))?;
     }
 
     Ok(())
 }
 
-fn collect_matching_ids<I, T>(iter: I, value: RegexOr<u32>) -> T
+fn on_update_tran_ext(
+    tran: &mut MappedTransition,
+    update: Option<&UpdateTransitionExt>,
+) -> Result<()> {
+    // Drop the ext of transitions that changed to a default type
+    if !tran.type_.has_ext() {
+        if update.is_some() {
+            bail!("`ext` is not stored for type `{:?}`", tran.type_);
+        }
+        tran.ext = None;
+        return Ok(());
+    }
+
+    // Chain animations are only played by chain animation transitions
+    let has_chain_update = update.is_some_and(|u| u.chain_anims.is_some());
+    if has_chain_update && tran.type_ != TransitionType::ChainAnimation {
+        bail!("`chain_anims` are not stored for type `{:?}`", tran.type_);
+    }
+
+    // Create the ext of transitions that changed from a default type
+    let ext = match &mut tran.ext {
+        Some(ext) => ext,
+        None => match update.and_then(|u| u.duration) {
+            Some(duration) => tran.ext.insert(TransitionExt {
+                duration,
+                intermediate_anims: Vec::new(),
+                chain_anims: Vec::new(),
+            }),
+            None => bail!("`ext.duration` is required for type `{:?}`", tran.type_),
+        },
+    };
+
+    let Some(update) = update else {
+        return Ok(());
+    };
+
+    // Update transition duration
+    if let Some(duration) = update.duration {
+        ext.duration = duration;
+    }
+
+    // Update intermediate animations of transition
+    if let Some(intermediate_anims) = &update.intermediate_anims {
+        for intermediate_anim in intermediate_anims.iter() {
+            match &intermediate_anim.body {
+                IntermediateAnimationPatchBody::Add(a) => on_add_intermediate_anim(ext, a)?,
+                IntermediateAnimationPatchBody::Delete(d) => on_delete_intermediate_anim(ext, d),
+            }
+        }
+    }
+
+    // Update chain animations of transition
+    if let Some(chain_anims) = &update.chain_anims {
+        for chain_anim in chain_anims.iter() {
+            match &chain_anim.body {
+                ChainAnimationPatchBody::Add(a) => on_add_chain_anim(ext, a)?,
+                ChainAnimationPatchBody::Delete(d) => on_delete_chain_anim(ext, d),
+                ChainAnimationPatchBody::Update(u) => on_update_chain_anim(ext, u),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn on_add_intermediate_anim(ext: &mut TransitionExt, add: &AddIntermediateAnimation) -> Result<()> {
+    // If the same pair of keys already existed, fail
+    let exists = ext
+        .intermediate_anims
+        .iter()
+        .any(|i| i.start_key == add.start_key && i.target_key == add.target_key);
+    if exists {
+        bail!(
+            "intermediate anim from `{}` to `{}` already exists",
+            add.start_key,
+            add.target_key
+        );
+    }
+
+    ext.intermediate_anims.push(add.clone());
+
+    Ok(())
+}
+
+fn on_delete_intermediate_anim(ext: &mut TransitionExt, delete: &DeleteIntermediateAnimation) {
+    let is_match = |key: &Option<RegexOr<String>>, value: &str| match key {
+        Some(RegexOr::Regex(re)) => re.is_match(value),
+        Some(RegexOr::Other(o)) => o == value,
+        None => true,
+    };
+
+    ext.intermediate_anims.retain(|i| {
+        !(is_match(&delete.start_key, &i.start_key) && is_match(&delete.target_key, &i.target_key))
+    });
+}
+
+fn on_add_chain_anim(ext: &mut TransitionExt, add: &AddChainAnimation) -> Result<()> {
+    // If a chain animation of the same id already existed, fail
+    if ext.chain_anims.iter().any(|c| c.id == add.id) {
+        bail!("chain anim `{}` already exists", add.id);
+    }
+
+    ext.chain_anims.push(add.clone());
+
+    Ok(())
+}
+
+fn on_delete_chain_anim(ext: &mut TransitionExt, delete: &DeleteChainAnimation) {
+    let Some(id) = &delete.id else {
+        ext.chain_anims.clear();
+        return;
+    };
+    let all_ids = ext.chain_anims.iter().map(|c| c.id);
+    let delete_ids: HashSet<_> = collect_matching_ids(all_ids, id);
+
+    ext.chain_anims.retain(|c| !delete_ids.contains(&c.id));
+}
+
+fn on_update_chain_anim(ext: &mut TransitionExt, update: &UpdateChainAnimation) {
+    let all_ids = ext.chain_anims.iter().map(|c| c.id);
+    let update_ids: HashSet<_> = collect_matching_ids(all_ids, &update.id);
+
+    let chain_anims = ext
+        .chain_anims
+        .iter_mut()
+        .filter(|c| update_ids.contains(&c.id));
+    for chain_anim in chain_anims {
+        if let Some(duration) = update.duration {
+            chain_anim.duration = duration;
+        }
+    }
+}
+
+fn on_add_layer_group(m_src: &mut MappedSource, add: AddLayerGroup) -> Result<()> {
+    // If a layer group of the same id already existed, fail
+    if m_src.layer_groups.iter().any(|g| g.id == add.id) {
+        bail!("layer group `{}` already exists", add.id);
+    }
+
+    m_src.layer_groups.push(add);
+
+    Ok(())
+}
+
+fn on_delete_layer_group(m_src: &mut MappedSource, delete: DeleteLayerGroup) -> Result<()> {
+    let all_ids = m_src.layer_groups.iter().map(|g| g.id);
+    let delete_ids: HashSet<_> = collect_matching_ids(all_ids, &delete.id);
+
+    m_src.layer_groups.retain(|g| !delete_ids.contains(&g.id));
+
+    Ok(())
+}
+
+fn on_update_layer_group(m_src: &mut MappedSource, update: UpdateLayerGroup) -> Result<()> {
+    let all_ids = m_src.layer_groups.iter().map(|g| g.id);
+    let update_ids: HashSet<_> = collect_matching_ids(all_ids, &update.id);
+
+    let groups = m_src
+        .layer_groups
+        .iter_mut()
+        .filter(|g| update_ids.contains(&g.id));
+    for group in groups {
+        // Update layer group name
+        if let Some(name) = &update.name {
+            group.name = name.clone();
+        }
+
+        // Update layers of layer group
+        if let Some(layers) = &update.layers {
+            for layer in layers.iter() {
+                match &layer.body {
+                    LayerPatchBody::Add(a) => on_add_layer(group, a)?,
+                    LayerPatchBody::Delete(d) => on_delete_layer(group, d),
+                    LayerPatchBody::Update(u) => on_update_layer(group, u),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn on_add_layer(group: &mut LayerGroup, add: &AddLayer) -> Result<()> {
+    // If a layer of the same id already existed, fail
+    if group.layers.iter().any(|l| l.id == add.id) {
+        bail!("layer group `{}` already has layer `{}`", group.id, add.id);
+    }
+
+    group.layers.push(add.clone());
+
+    Ok(())
+}
+
+fn on_delete_layer(group: &mut LayerGroup, delete: &DeleteLayer) {
+    let all_ids = group.layers.iter().map(|l| l.id);
+    let delete_ids: HashSet<_> = collect_matching_ids(all_ids, &delete.id);
+
+    group.layers.retain(|l| !delete_ids.contains(&l.id));
+}
+
+fn on_update_layer(group: &mut LayerGroup, update: &UpdateLayer) {
+    let all_ids = group.layers.iter().map(|l| l.id);
+    let update_ids: HashSet<_> = collect_matching_ids(all_ids, &update.id);
+
+    let layers = group
+        .layers
+        .iter_mut()
+        .filter(|l| update_ids.contains(&l.id));
+    for layer in layers {
+        if let Some(priority) = update.priority {
+            layer.priority = priority;
+        }
+        if let Some(weight) = update.weight {
+            layer.weight = weight;
+        }
+        if let Some(ease_in_time) = update.ease_in_time {
+            layer.ease_in_time = ease_in_time;
+        }
+        if let Some(ease_out_time) = update.ease_out_time {
+            layer.ease_out_time = ease_out_time;
+        }
+        if let Some(sync_id) = update.sync_id {
+            layer.sync_id = sync_id;
+        }
+    }
+}
+
+/// Collects the ids of the candidate animations that match both an id and a selector.
+///
+/// Candidates that are not animations of the source never match a selector.
+fn select_anim_ids<I>(
+    m_src: &MappedSource,
+    candidates: I,
+    id: &Option<IdSelector>,
+    select: &Option<AnimationSelector>,
+) -> Result<HashSet<u32>>
 where
     I: Iterator<Item = u32>,
-    T: FromIterator<u32>,
 {
-    match value {
-        RegexOr::Regex(re) => iter.filter(|i| re.is_match(&i.to_string())).collect(),
-        RegexOr::Other(o) => iter.filter(|i| *i == o).collect(),
+    if id.is_none() && select.is_none() {
+        bail!("either `id` or `select` is required");
     }
+
+    let mut ids: HashSet<_> = match id {
+        Some(id) => collect_matching_ids(candidates, id),
+        None => candidates.collect(),
+    };
+
+    if let Some(select) = select {
+        ids.retain(|id| match m_src.anims.get(id) {
+            Some(anim) => select.is_match(anim),
+            None => false,
+        });
+    }
+
+    Ok(ids)
+}
+
+fn collect_matching_ids<I, T>(iter: I, value: &IdSelector) -> T
+where
+    I: Iterator<Item = u32>,
+    T: FromIterator<u32>,
+{
+    iter.filter(|i| value.is_match(*i)).collect()
 }
 
 #[cfg(test)]
@@ -349,8 +1106,10 @@ mod tests {
     use super::{apply, PatchFile};
     use super::{AddAnimation, UpdateAnimation};
     use super::{AddTransition, DeleteTransition};
-    use crate::regex_or::RegexOr;
-    use crate::source::{DefaultTransitions, Model, Transition, TransitionType};
+    use super::{AnimationPatchBody, TransitionPatchBody};
+    use crate::id_selector::IdSelector;
+    use crate::source::{ChainAnimation, IntermediateAnimation, TransitionExt};
+    use crate::source::{DefaultTransitions, Layer, LayerGroup, Model, Transition, TransitionType};
     use crate::source::{MappedAnimation, MappedSource, MappedTransition};
     use indoc::indoc;
     use std::collections::BTreeMap;
@@ -358,6 +1117,8 @@ mod tests {
     #[test]
     fn test_patch_file_ser() {
         let patch_file = PatchFile {
+            model: None,
+            default_trans: None,
             anims: vec![
                 AddAnimation {
                     id: 0,
@@ -371,16 +1132,19 @@ mod tests {
                 }
                 .into(),
                 UpdateAnimation {
-                    id: RegexOr::Other(1),
+                    id: Some(IdSelector::Id(1)),
+                    select: None,
                     path: None,
                     index: Some(2),
                     trans: Some(vec![
                         DeleteTransition {
-                            id: RegexOr::Regex(".*".try_into().unwrap()),
+                            id: Some(IdSelector::regex(".*").unwrap()),
+                            select: None,
                         }
                         .into(),
                         AddTransition {
-                            id: RegexOr::Other(3),
+                            id: Some(IdSelector::Id(3)),
+                            select: None,
                             type_: TransitionType::ChainAnimation,
                             ext: None,
                         }
@@ -389,6 +1153,7 @@ mod tests {
                 }
                 .into(),
             ],
+            layer_groups: Vec::new(),
         };
 
         let expected = indoc! {"
@@ -415,6 +1180,94 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_patch_file_json_de() {
+        let json = indoc! {r#"
+            {
+              "anims": [
+                { "delete": { "id": "/[23]/" } },
+                {
+                  "update": {
+                    "id": 1,
+                    "trans": [{ "add": { "id": "/.*/", "type": "blend" } }]
+                  }
+                }
+              ]
+            }
+        "#};
+
+        let patch_file = PatchFile::from_json_reader(json.as_bytes()).unwrap();
+        assert_eq!(2, patch_file.anims.len());
+
+        match &patch_file.anims[0].body {
+            AnimationPatchBody::Delete(d) => {
+                assert!(
+                    matches!(&d.id, Some(IdSelector::Regex(re)) if re.is_match("2") && !re.is_match("12"))
+                )
+            }
+            _ => panic!("expected a `delete` action"),
+        }
+
+        match &patch_file.anims[1].body {
+            AnimationPatchBody::Update(u) => {
+                assert!(matches!(u.id, Some(IdSelector::Id(1))));
+                let trans = u.trans.as_ref().unwrap();
+                match &trans[0].body {
+                    TransitionPatchBody::Add(a) => {
+                        assert!(matches!(a.id, Some(IdSelector::Regex(_))))
+                    }
+                    _ => panic!("expected an `add` action"),
+                }
+            }
+            _ => panic!("expected an `update` action"),
+        }
+
+        // Non-finite numbers are written as strings, as in source files
+        let json = r#"{ "default_trans": { "sync_duration": "NaN", "non_sync_duration": "inf" } }"#;
+        let patch_file = PatchFile::from_json_reader(json.as_bytes()).unwrap();
+        let update = patch_file.default_trans.unwrap();
+        assert!(update.sync_duration.unwrap().is_nan());
+        assert_eq!(Some(f32::INFINITY), update.non_sync_duration);
+    }
+
+    #[test]
+    fn test_patch_file_toml_de() {
+        let toml = indoc! {r#"
+            [[anims]]
+            delete = { id = "/[23]/" }
+
+            [[anims]]
+            [anims.update]
+            id = 1
+            path = "foo/bar"
+
+            [[anims.update.trans]]
+            add = { id = 2, type = "blend", ext = { duration = 0.5, intermediate_anims = [], chain_anims = [] } }
+        "#};
+
+        let patch_file = PatchFile::from_toml_reader(toml.as_bytes()).unwrap();
+        assert_eq!(2, patch_file.anims.len());
+        assert!(matches!(
+            &patch_file.anims[0].body,
+            AnimationPatchBody::Delete(d) if matches!(&d.id, Some(IdSelector::Regex(re)) if re.is_match("2") && !re.is_match("12"))
+        ));
+
+        match &patch_file.anims[1].body {
+            AnimationPatchBody::Update(u) => {
+                assert_eq!(Some("foo/bar"), u.path.as_deref());
+                let trans = u.trans.as_ref().unwrap();
+                match &trans[0].body {
+                    TransitionPatchBody::Add(a) => {
+                        assert!(matches!(a.id, Some(IdSelector::Id(2))));
+                        assert_eq!(0.5, a.ext.as_ref().unwrap().duration);
+                    }
+                    _ => panic!("expected an `add` action"),
+                }
+            }
+            _ => panic!("expected an `update` action"),
+        }
+    }
+
     #[test]
     fn test_patch_file_apply() {
         let mut m_src = MappedSource {
@@ -460,6 +1313,8 @@ mod tests {
         }
 
         let patch_file = PatchFile {
+            model: None,
+            default_trans: None,
             anims: vec![
                 // Add `ondie` animation
                 AddAnimation {
@@ -471,11 +1326,13 @@ mod tests {
                 .into(),
                 // Add transition from every animation to `ondie`
                 UpdateAnimation {
-                    id: RegexOr::Regex(".*".try_into().unwrap()),
+                    id: Some(IdSelector::regex(".*").unwrap()),
+                    select: None,
                     path: None,
                     index: None,
                     trans: Some(vec![AddTransition {
-                        id: RegexOr::Other(4),
+                        id: Some(IdSelector::Id(4)),
+                        select: None,
                         type_: TransitionType::DefaultNonSync,
                         ext: None,
                     }
@@ -492,11 +1349,13 @@ mod tests {
                 .into(),
                 // Add transition from `spawn` to every other animation
                 UpdateAnimation {
-                    id: RegexOr::Other(5),
+                    id: Some(IdSelector::Id(5)),
+                    select: None,
                     path: None,
                     index: None,
                     trans: Some(vec![AddTransition {
-                        id: RegexOr::Regex(".*".try_into().unwrap()),
+                        id: Some(IdSelector::regex(".*").unwrap()),
+                        select: None,
                         type_: TransitionType::DefaultNonSync,
                         ext: None,
                     }
@@ -505,22 +1364,26 @@ mod tests {
                 .into(),
                 // Delete transition from `spawn` to `ondie`
                 UpdateAnimation {
-                    id: RegexOr::Other(5),
+                    id: Some(IdSelector::Id(5)),
+                    select: None,
                     path: None,
                     index: None,
                     trans: Some(vec![DeleteTransition {
-                        id: RegexOr::Other(4),
+                        id: Some(IdSelector::Id(4)),
+                        select: None,
                     }
                     .into()]),
                 }
                 .into(),
                 // Add transition from `ondie` to `spawn`
                 UpdateAnimation {
-                    id: RegexOr::Other(4),
+                    id: Some(IdSelector::Id(4)),
+                    select: None,
                     path: None,
                     index: None,
                     trans: Some(vec![AddTransition {
-                        id: RegexOr::Other(5),
+                        id: Some(IdSelector::Id(5)),
+                        select: None,
                         type_: TransitionType::DefaultNonSync,
                         ext: None,
                     }
@@ -528,6 +1391,7 @@ mod tests {
                 }
                 .into(),
             ],
+            layer_groups: Vec::new(),
         };
 
         // Apply patch to source
@@ -542,6 +1406,490 @@ mod tests {
         assert_trans_ids_eq(&m_src, 5, &[0, 1, 2, 3]);
     }
 
+    #[test]
+    fn test_patch_model_and_default_trans() {
+        let mut m_src = MappedSource {
+            model: Model {
+                path: "model.nif".to_string(),
+                root: "Root".to_string(),
+            },
+            default_trans: DefaultTransitions {
+                sync_type: TransitionType::Morph,
+                sync_duration: 0.25,
+                non_sync_type: TransitionType::Blend,
+                non_sync_duration: 0.25,
+            },
+            anims: BTreeMap::new(),
+            layer_groups: Vec::new(),
+        };
+
+        let yaml = indoc! {"
+            model:
+              path: new/model.nif
+            default_trans:
+              non_sync_type: crossfade
+              sync_duration: 0.5
+        "};
+        let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+        apply(&mut m_src, patch_file).unwrap();
+
+        assert_eq!("new/model.nif", m_src.model.path);
+        assert_eq!("Root", m_src.model.root);
+        assert_eq!(TransitionType::Morph, m_src.default_trans.sync_type);
+        assert_eq!(0.5, m_src.default_trans.sync_duration);
+        assert_eq!(TransitionType::Crossfade, m_src.default_trans.non_sync_type);
+        assert_eq!(0.25, m_src.default_trans.non_sync_duration);
+
+        let yaml = "default_trans: { sync_type: default_non_sync, sync_duration: 1.0 }";
+        let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+        let err = apply(&mut m_src, patch_file).unwrap_err();
+        assert_eq!(
+            "update default trans: `sync_type` cannot be `DefaultNonSync`",
+            format!("{:#}", err)
+        );
+        assert_eq!(0.5, m_src.default_trans.sync_duration);
+
+        let cases = [
+            (
+                "{ non_sync_type: chain_animation }",
+                "`non_sync_type` cannot be `ChainAnimation`",
+            ),
+            (
+                "{ sync_duration: -0.5 }",
+                "`sync_duration` must be finite and non-negative, not `-0.5`",
+            ),
+            (
+                "{ non_sync_duration: .nan }",
+                "`non_sync_duration` must be finite and non-negative, not `NaN`",
+            ),
+        ];
+        for (update, expected) in cases {
+            let yaml = format!("default_trans: {}", update);
+            let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+            let err = apply(&mut m_src, patch_file).unwrap_err();
+            assert_eq!(
+                format!("update default trans: {}", expected),
+                format!("{:#}", err)
+            );
+        }
+        assert_eq!(TransitionType::Crossfade, m_src.default_trans.non_sync_type);
+
+        let yaml = "default_trans: { sync_type: fade }";
+        assert!(PatchFile::from_reader(yaml.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_patch_add_tran_checks_ext() {
+        let make_anim = |path: &str| MappedAnimation {
+            path: path.to_string(),
+            index: 0,
+            trans: BTreeMap::new(),
+        };
+        let mut m_src = MappedSource {
+            model: Model {
+                path: "model.nif".to_string(),
+                root: "Root".to_string(),
+            },
+            default_trans: DefaultTransitions {
+                sync_type: TransitionType::Morph,
+                sync_duration: 0.25,
+                non_sync_type: TransitionType::Blend,
+                non_sync_duration: 0.25,
+            },
+            anims: BTreeMap::from([(0, make_anim("idle.kf")), (1, make_anim("run.kf"))]),
+            layer_groups: Vec::new(),
+        };
+
+        let yaml = "anims: [{ update: { id: 0, trans: [{ add: { id: 1, type: blend } }] } }]";
+        let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+        let err = apply(&mut m_src, patch_file).unwrap_err();
+        assert_eq!(
+            "add tran `1` to anim `0`: `ext` is required for type `Blend`",
+            format!("{:#}", err)
+        );
+
+        let yaml = "anims: [{ update: { id: 0, trans: [{ add: { id: 1, type: default_sync, \
+                    ext: { duration: 0.5, intermediate_anims: [], chain_anims: [] } } }] } }]";
+        let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+        let err = apply(&mut m_src, patch_file).unwrap_err();
+        assert_eq!(
+            "add tran `1` to anim `0`: `ext` is not stored for type `DefaultSync`",
+            format!("{:#}", err)
+        );
+    }
+
+    #[test]
+    fn test_patch_tran_ext() {
+        let mut m_src = MappedSource {
+            model: Model {
+                path: "model.nif".to_string(),
+                root: "Root".to_string(),
+            },
+            default_trans: DefaultTransitions {
+                sync_type: TransitionType::Morph,
+                sync_duration: 0.25,
+                non_sync_type: TransitionType::Blend,
+                non_sync_duration: 0.25,
+            },
+            anims: BTreeMap::new(),
+            layer_groups: Vec::new(),
+        };
+        let ext = TransitionExt {
+            duration: 0.25,
+            intermediate_anims: vec![
+                IntermediateAnimation {
+                    start_key: "idle_end".to_string(),
+                    target_key: "run_start".to_string(),
+                },
+                IntermediateAnimation {
+                    start_key: "idle_mid".to_string(),
+                    target_key: "run_start".to_string(),
+                },
+            ],
+            chain_anims: vec![
+                ChainAnimation {
+                    id: 2,
+                    duration: 0.5,
+                },
+                ChainAnimation {
+                    id: 3,
+                    duration: 0.5,
+                },
+            ],
+        };
+        m_src.anims.insert(
+            0,
+            MappedAnimation {
+                path: "idle.kf".to_string(),
+                index: 0,
+                trans: BTreeMap::from([
+                    (
+                        1,
+                        MappedTransition {
+                            type_: TransitionType::ChainAnimation,
+                            ext: Some(ext),
+                        },
+                    ),
+                    (
+                        2,
+                        MappedTransition {
+                            type_: TransitionType::DefaultSync,
+                            ext: None,
+                        },
+                    ),
+                ]),
+            },
+        );
+
+        let yaml = indoc! {"
+            anims:
+            - update:
+                id: 0
+                trans:
+                - update:
+                    id: 1
+                    ext:
+                      intermediate_anims:
+                      - delete:
+                          start_key: /_mid$/
+                      - add:
+                          start_key: idle_end
+                          target_key: run_loop
+                      chain_anims:
+                      - update:
+                          id: 3
+                          duration: 1.0
+                      - delete:
+                          id: 2
+                      - add:
+                          id: 4
+                          duration: 0.1
+                - update:
+                    id: 2
+                    type: blend
+                    ext:
+                      duration: 0.75
+        "};
+        let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+        apply(&mut m_src, patch_file).unwrap();
+
+        // Unmentioned fields are kept
+        let tran = &m_src.anims[&0].trans[&1];
+        assert_eq!(TransitionType::ChainAnimation, tran.type_);
+        let ext = tran.ext.as_ref().unwrap();
+        assert_eq!(0.25, ext.duration);
+        let keys: Vec<_> = ext
+            .intermediate_anims
+            .iter()
+            .map(|i| (i.start_key.as_str(), i.target_key.as_str()))
+            .collect();
+        assert_eq!(
+            vec![("idle_end", "run_start"), ("idle_end", "run_loop")],
+            keys
+        );
+        let chain: Vec<_> = ext.chain_anims.iter().map(|c| (c.id, c.duration)).collect();
+        assert_eq!(vec![(3, 1.0), (4, 0.1)], chain);
+
+        // Ext is created for transitions that change from a default type
+        let tran = &m_src.anims[&0].trans[&2];
+        assert_eq!(TransitionType::Blend, tran.type_);
+        assert_eq!(0.75, tran.ext.as_ref().unwrap().duration);
+
+        let apply_yaml = |m_src: &mut MappedSource, yaml: &str| {
+            let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+            apply(m_src, patch_file).map_err(|e| format!("{:#}", e))
+        };
+
+        // Ext is dropped for transitions that change to a default type
+        apply_yaml(
+            &mut m_src,
+            "anims: [{ update: { id: 0, trans: [{ update: { id: 2, type: default_sync } }] } }]",
+        )
+        .unwrap();
+        assert!(m_src.anims[&0].trans[&2].ext.is_none());
+
+        let err = apply_yaml(
+            &mut m_src,
+            "anims: [{ update: { id: 0, trans: [{ update: { id: 2, type: morph } }] } }]",
+        )
+        .unwrap_err();
+        assert_eq!(
+            "update tran `2` of anim `0`: `ext.duration` is required for type `Morph`",
+            err
+        );
+
+        let err = apply_yaml(
+            &mut m_src,
+            "anims: [{ update: { id: 0, trans: [{ update: { id: 1, ext: { chain_anims: \
+             [{ add: { id: 3, duration: 0.0 } }] } } }] } }]",
+        )
+        .unwrap_err();
+        assert_eq!(
+            "update tran `1` of anim `0`: chain anim `3` already exists",
+            err
+        );
+
+        // Chain anims are dropped for transitions that change to another type
+        apply_yaml(
+            &mut m_src,
+            "anims: [{ update: { id: 0, trans: [{ update: { id: 1, type: morph } }] } }]",
+        )
+        .unwrap();
+        let ext = m_src.anims[&0].trans[&1].ext.as_ref().unwrap();
+        assert!(ext.chain_anims.is_empty());
+        assert_eq!(2, ext.intermediate_anims.len());
+
+        // Nor can they be patched into transitions of another type
+        let err = apply_yaml(
+            &mut m_src,
+            "anims: [{ update: { id: 0, trans: [{ update: { id: 1, ext: { \
+             chain_anims: [{ add: { id: 4, duration: 0.1 } }] } } }] } }]",
+        )
+        .unwrap_err();
+        assert_eq!(
+            "update tran `1` of anim `0`: `chain_anims` are not stored for type `Morph`",
+            err
+        );
+
+        // Lists are cleared by deleting without keys or ids
+        let yaml = indoc! {"
+            anims:
+            - update:
+                id: 0
+                trans:
+                - update:
+                    id: 1
+                    type: chain_animation
+                    ext:
+                      intermediate_anims:
+                      - delete: {}
+                      chain_anims:
+                      - add: { id: 2, duration: 0.5 }
+                      - add: { id: 3, duration: 0.5 }
+                      - delete: {}
+                      - add: { id: 4, duration: 0.1 }
+        "};
+        apply_yaml(&mut m_src, yaml).unwrap();
+        let ext = m_src.anims[&0].trans[&1].ext.as_ref().unwrap();
+        assert!(ext.intermediate_anims.is_empty());
+        let chain: Vec<_> = ext.chain_anims.iter().map(|c| (c.id, c.duration)).collect();
+        assert_eq!(vec![(4, 0.1)], chain);
+    }
+
+    #[test]
+    fn test_patch_layer_groups() {
+        let make_layer = |id, priority| Layer {
+            id,
+            priority,
+            weight: 1.0,
+            ease_in_time: 0.0,
+            ease_out_time: 0.0,
+            sync_id: 0,
+        };
+        let mut m_src = MappedSource {
+            model: Model {
+                path: "model.nif".to_string(),
+                root: "Root".to_string(),
+            },
+            default_trans: DefaultTransitions {
+                sync_type: TransitionType::Morph,
+                sync_duration: 0.25,
+                non_sync_type: TransitionType::Blend,
+                non_sync_duration: 0.25,
+            },
+            anims: BTreeMap::new(),
+            layer_groups: vec![
+                LayerGroup {
+                    id: 0,
+                    name: "upper".to_string(),
+                    layers: vec![make_layer(1, 0), make_layer(2, 0), make_layer(12, 0)],
+                },
+                LayerGroup {
+                    id: 1,
+                    name: "lower".to_string(),
+                    layers: vec![make_layer(1, 0)],
+                },
+            ],
+        };
+
+        let yaml = indoc! {"
+            layer_groups:
+            - update:
+                id: /.*/
+                layers:
+                - update:
+                    id: [1, '>10']
+                    priority: 3
+                    ease_in_time: 0.5
+            - update:
+                id: 0
+                name: torso
+                layers:
+                - delete:
+                    id: 2
+                - add:
+                    id: 3
+                    priority: -1
+                    weight: 0.5
+                    ease_in_time: 0.1
+                    ease_out_time: 0.2
+                    sync_id: 4
+            - delete:
+                id: 1
+            - add:
+                id: 2
+                name: head
+                layers: []
+        "};
+        let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+        assert!(patch_file.anims.is_empty());
+        apply(&mut m_src, patch_file).unwrap();
+
+        let mut updated = make_layer(1, 3);
+        updated.ease_in_time = 0.5;
+        let mut updated_12 = updated.clone();
+        updated_12.id = 12;
+        let expected = vec![
+            LayerGroup {
+                id: 0,
+                name: "torso".to_string(),
+                layers: vec![
+                    updated,
+                    updated_12,
+                    Layer {
+                        id: 3,
+                        priority: -1,
+                        weight: 0.5,
+                        ease_in_time: 0.1,
+                        ease_out_time: 0.2,
+                        sync_id: 4,
+                    },
+                ],
+            },
+            LayerGroup {
+                id: 2,
+                name: "head".to_string(),
+                layers: Vec::new(),
+            },
+        ];
+        assert_eq!(expected, m_src.layer_groups);
+
+        let yaml = "layer_groups: [{ update: { id: 0, layers: [{ add: { id: 3, priority: 0, \
+                    weight: 1, ease_in_time: 0, ease_out_time: 0, sync_id: 0 } }] } }]";
+        let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+        let err = apply(&mut m_src, patch_file).unwrap_err();
+        assert_eq!("layer group `0` already has layer `3`", err.to_string());
+    }
+
+    #[test]
+    fn test_patch_anim_selectors() {
+        let make_anim = |path: &str, index| MappedAnimation {
+            path: path.to_string(),
+            index,
+            trans: BTreeMap::new(),
+        };
+        let mut m_src = MappedSource {
+            model: Model {
+                path: "model.nif".to_string(),
+                root: "Root".to_string(),
+            },
+            default_trans: DefaultTransitions {
+                sync_type: TransitionType::Morph,
+                sync_duration: 0.25,
+                non_sync_type: TransitionType::Blend,
+                non_sync_duration: 0.25,
+            },
+            anims: BTreeMap::from([
+                (0, make_anim("./mech/mech_gunbot_h_onhit.kf", 0)),
+                (1, make_anim("./mech/mech_gunbot_h_onhit.kf", 1)),
+                (2, make_anim("./mech/mech_gunbot_m_idle.kf", 0)),
+                (3, make_anim(".\\mech\\mech_gunbot_h_ondie.kf", 0)),
+            ]),
+            layer_groups: Vec::new(),
+        };
+
+        let yaml = indoc! {"
+            anims:
+            - update:
+                select: { path: '*_h_*.kf', index: 0 }
+                trans:
+                - add: { select: { path: /idle/ }, type: default_non_sync }
+            - update:
+                id: /[12]/
+                select: { path: '*_h_onhit.kf' }
+                trans:
+                - add: { select: { path: 'mech/*.kf' }, type: default_sync }
+                - delete: { select: { path: '*_ondie.kf' } }
+            - delete:
+                select: { path: 'MECH_GUNBOT_M_*.KF' }
+        "};
+        let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+        apply(&mut m_src, patch_file).unwrap();
+
+        assert_eq!(
+            vec![0, 1, 3],
+            m_src.anims.keys().cloned().collect::<Vec<_>>()
+        );
+        assert_trans_ids_eq(&m_src, 0, &[]);
+        assert_trans_ids_eq(&m_src, 1, &[0]);
+        assert_trans_ids_eq(&m_src, 3, &[]);
+
+        // A lone `/` is a glob rather than an empty regex
+        let yaml = "anims: [{ delete: { select: { path: / } } }]";
+        assert!(PatchFile::from_reader(yaml.as_bytes()).is_ok());
+
+        // Path regexes ignore case, like globs
+        let yaml = "anims: [{ update: { select: { path: /ONDIE/ }, index: 5 } }]";
+        let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+        apply(&mut m_src, patch_file).unwrap();
+        assert_eq!(5, m_src.anims[&3].index);
+
+        let yaml = "anims: [{ update: { index: 2 } }]";
+        let patch_file = PatchFile::from_reader(yaml.as_bytes()).unwrap();
+        let err = apply(&mut m_src, patch_file).unwrap_err();
+        assert_eq!("either `id` or `select` is required", err.to_string());
+    }
+
     fn assert_trans_ids_eq(m_src: &MappedSource, anim_id: u32, expected_ids: &[u32]) {
         let anim = match m_src.anims.get(&anim_id) {
             Some(a) => a,
@@ -33,7 +33,8 @@ where
     {
         let value = serde_yaml::Value::deserialize(deserializer)?;
         if let serde_yaml::Value::String(ref s) = value {
-            if s.starts_with("/") && s.ends_with("/") {
+            // A lone `/` is too short to hold a regex, and is left to `T`
+            if s.len() >= 2 && s.starts_with('/') && s.ends_with('/') {
                 let re_substr = &s[1..s.len() - 1];
                 let re = re_substr.to_owned().try_into().map_err(D::Error::custom)?;
                 return Ok(RegexOr::Regex(re));
@@ -66,4 +67,16 @@ mod tests {
         let actual = serde_yaml::to_string(&value).unwrap();
         assert_eq!(expected, actual.trim());
     }
+
+    #[test]
+    fn test_wild_or_de() {
+        let value: RegexOr<String> = serde_yaml::from_str("/a+/").unwrap();
+        assert!(matches!(value, RegexOr::Regex(re) if re.as_str() == "a+"));
+
+        let value: RegexOr<String> = serde_yaml::from_str("//").unwrap();
+        assert!(matches!(value, RegexOr::Regex(re) if re.as_str().is_empty()));
+
+        let value: RegexOr<String> = serde_yaml::from_str("/").unwrap();
+        assert!(matches!(value, RegexOr::Other(s) if s == "/"));
+    }
 }
@@ -0,0 +1,330 @@
+use crate::matrix::make_matrix;
+use crate::source::SourceFileBody;
+use crate::text::type_name;
+use crate::validate::Problem;
+use anyhow::Result;
+use indoc::indoc;
+use serde::Serialize;
+use tera::{Context as TeraContext, Tera};
+
+#[derive(Serialize)]
+struct AnimRow {
+    id: u32,
+    name: String,
+    path: String,
+    index: u32,
+    num_trans: usize,
+}
+
+#[derive(Serialize)]
+struct LayerGroupRow {
+    id: u32,
+    name: String,
+    layers: Vec<LayerRow>,
+}
+
+#[derive(Serialize)]
+struct LayerRow {
+    id: u32,
+    priority: i32,
+    weight: String,
+    ease_in_time: String,
+    ease_out_time: String,
+    sync_id: u32,
+}
+
+#[derive(Serialize)]
+struct ProblemRow {
+    severity: String,
+    location: String,
+    message: String,
+}
+
+/// Makes a self-contained HTML page that describes a source file, for reviews.
+///
+/// The page lists the model, the default transitions, the animations in a table that sorts by
+/// the clicked column, the transition matrix, the layer groups, and the given problems.
+pub fn make_report(title: &str, body: &SourceFileBody, problems: &[Problem]) -> Result<String> {
+    let anims: Vec<_> = body
+        .anims
+        .iter()
+        .map(|a| AnimRow {
+            id: a.id,
+            name: a.kf_stem(),
+            path: a.path.clone(),
+            index: a.index,
+            num_trans: a.trans.len(),
+        })
+        .collect();
+
+    // Groups are listed even without layers
+    let layer_groups: Vec<_> = body
+        .layer_groups
+        .iter()
+        .map(|g| LayerGroupRow {
+            id: g.id,
+            name: g.name.clone(),
+            layers: g
+                .layers
+                .iter()
+                .map(|l| LayerRow {
+                    id: l.id,
+                    priority: l.priority,
+                    weight: format!("{:?}", l.weight),
+                    ease_in_time: format!("{:?}", l.ease_in_time),
+                    ease_out_time: format!("{:?}", l.ease_out_time),
+                    sync_id: l.sync_id,
+                })
+                .collect(),
+        })
+        .collect();
+
+    let problems: Vec<_> = problems
+        .iter()
+        .map(|p| ProblemRow {
+            severity: p.severity.to_string(),
+            location: p.location.to_string(),
+            message: p.message.clone(),
+        })
+        .collect();
+
+    // Floats are formatted here, since the context cannot hold non-finite numbers
+    let default_trans = &body.default_trans;
+    let mut tera_ctx = TeraContext::new();
+    tera_ctx.insert("title", title);
+    tera_ctx.insert("model", &body.model);
+    tera_ctx.insert("sync_type", type_name(default_trans.sync_type));
+    tera_ctx.insert(
+        "sync_duration",
+        &format!("{:?}", default_trans.sync_duration),
+    );
+    tera_ctx.insert("non_sync_type", type_name(default_trans.non_sync_type));
+    tera_ctx.insert(
+        "non_sync_duration",
+        &format!("{:?}", default_trans.non_sync_duration),
+    );
+    tera_ctx.insert("anims", &anims);
+    tera_ctx.insert("matrix", &make_matrix(body));
+    tera_ctx.insert("layer_groups", &layer_groups);
+    tera_ctx.insert("problems", &problems);
+
+    render_report(&tera_ctx)
+}
+
+fn render_report(tera_ctx: &TeraContext) -> Result<String> {
+    let report_template = indoc! {r#"
+        <!DOCTYPE html>
+        <html lang="en">
+        <head>
+        <meta charset="utf-8">
+        <title>{{ title }}</title>
+        <style>
+        body { font-family: sans-serif; margin: 2em; }
+        table { border-collapse: collapse; margin-bottom: 1em; }
+        th, td { border: 1px solid #ccc; padding: 0.25em 0.5em; text-align: left; }
+        th { background: #eee; }
+        table.sortable th { cursor: pointer; }
+        table.matrix td { font-family: monospace; white-space: nowrap; }
+        .error { color: #b00; }
+        .warning { color: #a60; }
+        </style>
+        </head>
+        <body>
+        <h1>{{ title }}</h1>
+
+        <h2>Model</h2>
+        <table>
+        <tr><th>Path</th><td>{{ model.path }}</td></tr>
+        <tr><th>Root</th><td>{{ model.root }}</td></tr>
+        </table>
+
+        <h2>Default Transitions</h2>
+        <table>
+        <tr><th></th><th>Type</th><th>Duration</th></tr>
+        <tr><th>Sync</th><td>{{ sync_type }}</td><td>{{ sync_duration }}</td></tr>
+        <tr><th>Non-sync</th><td>{{ non_sync_type }}</td><td>{{ non_sync_duration }}</td></tr>
+        </table>
+
+        <h2>Animations</h2>
+        <table class="sortable">
+        <thead>
+        <tr><th>Id</th><th>Name</th><th>Path</th><th>Index</th><th>Transitions</th></tr>
+        </thead>
+        <tbody>
+        {%- for anim in anims %}
+        <tr>
+        <td>{{ anim.id }}</td><td>{{ anim.name }}</td><td>{{ anim.path }}</td>
+        <td>{{ anim.index }}</td><td>{{ anim.num_trans }}</td>
+        </tr>
+        {%- endfor %}
+        </tbody>
+        </table>
+
+        <h2>Transitions</h2>
+        <table class="matrix">
+        {%- for row in matrix %}
+        {%- set is_header_row = loop.first %}
+        <tr>
+        {%- for cell in row %}
+        {%- if is_header_row or loop.first %}<th>{{ cell }}</th>
+        {%- else %}<td>{{ cell }}</td>
+        {%- endif %}
+        {%- endfor -%}
+        </tr>
+        {%- endfor %}
+        </table>
+
+        <h2>Layer Groups</h2>
+        {%- if layer_groups %}
+        {%- for group in layer_groups %}
+        <h3>{{ group.name }} ({{ group.id }})</h3>
+        {%- if group.layers %}
+        <table>
+        <tr>
+        <th>Layer</th><th>Priority</th><th>Weight</th><th>Ease In</th><th>Ease Out</th><th>Sync</th>
+        </tr>
+        {%- for layer in group.layers %}
+        <tr>
+        <td>{{ layer.id }}</td><td>{{ layer.priority }}</td><td>{{ layer.weight }}</td>
+        <td>{{ layer.ease_in_time }}</td><td>{{ layer.ease_out_time }}</td>
+        <td>{{ layer.sync_id }}</td>
+        </tr>
+        {%- endfor %}
+        </table>
+        {%- else %}
+        <p>No layers.</p>
+        {%- endif %}
+        {%- endfor %}
+        {%- else %}
+        <p>None.</p>
+        {%- endif %}
+
+        <h2>Problems</h2>
+        {%- if problems %}
+        <ul>
+        {%- for problem in problems %}
+        <li class="{{ problem.severity }}">
+        {{ problem.severity }}: {{ problem.location }}: {{ problem.message }}
+        </li>
+        {%- endfor %}
+        </ul>
+        {%- else %}
+        <p>None.</p>
+        {%- endif %}
+
+        <script>
+        for (const table of document.querySelectorAll("table.sortable")) {
+            const tbody = table.tBodies[0];
+            table.querySelectorAll("th").forEach((th, col) => {
+                let ascending = true;
+                th.addEventListener("click", () => {
+                    const key = (row) => row.cells[col].textContent;
+                    const rows = Array.from(tbody.rows);
+                    const numeric = rows.every((row) => !isNaN(parseFloat(key(row))));
+                    rows.sort((a, b) => numeric
+                        ? parseFloat(key(a)) - parseFloat(key(b))
+                        : key(a).localeCompare(key(b)));
+                    if (!ascending) {
+                        rows.reverse();
+                    }
+                    ascending = !ascending;
+                    tbody.append(...rows);
+                });
+            });
+        }
+        </script>
+        </body>
+        </html>
+    "#};
+
+    let mut tera = Tera::default();
+    // The `.html` name turns on escaping of the inserted values
+    tera.add_raw_template("report.html", report_template)?;
+
+    let result = tera.render("report.html", tera_ctx)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::make_report;
+    use crate::source::{Animation, DefaultTransitions, Layer, LayerGroup, Model};
+    use crate::source::{SourceFileBody, Transition, TransitionType};
+    use crate::validate::check_integrity;
+
+    #[test]
+    fn test_make_report() {
+        let body = SourceFileBody {
+            model: Model {
+                path: "model.nif".to_string(),
+                root: "<Root>".to_string(),
+            },
+            default_trans: DefaultTransitions {
+                sync_type: TransitionType::Morph,
+                sync_duration: f32::NAN,
+                non_sync_type: TransitionType::Blend,
+                non_sync_duration: 0.25,
+            },
+            anims: vec![
+                Animation {
+                    id: 0,
+                    path: "./mech/idle.kf".to_string(),
+                    index: 2,
+                    trans: vec![Transition {
+                        id: 7,
+                        type_: TransitionType::DefaultSync,
+                        ext: None,
+                    }],
+                },
+                Animation {
+                    id: 1,
+                    path: "run.kf".to_string(),
+                    index: 0,
+                    trans: Vec::new(),
+                },
+            ],
+            layer_groups: vec![
+                LayerGroup {
+                    id: 3,
+                    name: "upper".to_string(),
+                    layers: vec![Layer {
+                        id: 1,
+                        priority: -1,
+                        weight: 0.5,
+                        ease_in_time: 0.1,
+                        ease_out_time: 0.2,
+                        sync_id: 0,
+                    }],
+                },
+                LayerGroup {
+                    id: 6,
+                    name: "additive".to_string(),
+                    layers: Vec::new(),
+                },
+            ],
+        };
+        let problems = check_integrity(&body);
+
+        let report = make_report("mech & co", &body, &problems).unwrap();
+        let expected_lines = [
+            "<title>mech &amp; co</title>",
+            "<tr><th>Root</th><td>&lt;Root&gt;</td></tr>",
+            "<tr><th>Sync</th><td>morph</td><td>NaN</td></tr>",
+            "<td>0</td><td>idle</td><td>.&#x2F;mech&#x2F;idle.kf</td>",
+            "<td>2</td><td>1</td>",
+            "<tr><th></th><th>idle (0)</th><th>run (1)</th></tr>",
+            "<tr><th>idle (0)</th><td></td><td></td></tr>",
+            "<h3>upper (3)</h3>",
+            "<td>1</td><td>-1</td><td>0.5</td>",
+            "<td>0.1</td><td>0.2</td>",
+            "<td>0</td>",
+            "<h3>additive (6)</h3>",
+            "<p>No layers.</p>",
+            "error: anim 0, trans 7: target anim 7 does not exist",
+            "<li class=\"error\">",
+        ];
+        for line in expected_lines {
+            assert!(report.lines().any(|l| l == line), "missing {:?}", line);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use crate::bin::{DecodeError, Limits, StringEncoding};
+use crate::source::SourceFile;
+use crate::text;
+use anyhow::{Context, Result};
+use std::fmt;
+
+/// The first byte at which a re-encoded file differs from the original.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub offset: usize,
+    pub expected: Option<u8>,
+    pub actual: Option<u8>,
+    pub field: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fmt_byte = |b: Option<u8>| match b {
+            Some(b) => format!("{:#04x}", b),
+            None => "end of file".to_string(),
+        };
+
+        write!(
+            f,
+            "first difference at offset {:#x} in `{}`: expected {}, found {}",
+            self.offset,
+            self.field,
+            fmt_byte(self.expected),
+            fmt_byte(self.actual)
+        )
+    }
+}
+
+/// Decodes the given `.kfm` file contents, encodes them again in the same format and compares
+/// the result with the original bytes.
+///
+/// Strings of binary files, and the lines of text files, are decoded and encoded with
+/// `string_encoding`. Returns `None` if both are identical.
+pub fn verify(bytes: &[u8], string_encoding: StringEncoding) -> Result<Option<Mismatch>> {
+    let is_text = text::is_text_kfm(bytes);
+
+    let src_file = if is_text {
+        SourceFile::from_kfm_text_reader_with(bytes, string_encoding).context("decode file")?
+    } else {
+        let limits = Limits {
+            max_total_bytes: bytes.len() as u64,
+            ..Limits::default()
+        };
+        SourceFile::from_kfm_reader_with(bytes, limits, string_encoding).context("decode file")?
+    };
+
+    let mut actual = Vec::new();
+    if is_text {
+        src_file.to_kfm_text_writer(&mut actual)
+    } else {
+        src_file.to_kfm_writer(&mut actual)
+    }
+    .context("encode file")?;
+
+    let offset = match bytes.iter().zip(actual.iter()).position(|(a, b)| a != b) {
+        Some(i) => i,
+        None if bytes.len() == actual.len() => return Ok(None),
+        None => bytes.len().min(actual.len()),
+    };
+
+    let field = if is_text {
+        let line_num = bytes[..offset].iter().filter(|b| **b == b'\n').count() + 1;
+        format!("line {}", line_num)
+    } else {
+        locate_field(bytes, offset, string_encoding)
+    };
+
+    Ok(Some(Mismatch {
+        offset,
+        expected: bytes.get(offset).cloned(),
+        actual: actual.get(offset).cloned(),
+        field,
+    }))
+}
+
+/// Finds the field of a binary `.kfm` file that the byte at `offset` belongs to.
+///
+/// The file is decoded again, truncated right before `offset`. The first field that cannot be
+/// read is the one containing that byte.
+fn locate_field(bytes: &[u8], offset: usize, string_encoding: StringEncoding) -> String {
+    let truncated = &bytes[..offset];
+    let err = match SourceFile::from_kfm_reader_with(truncated, Limits::default(), string_encoding)
+    {
+        Ok(_) => return "end of file".to_string(),
+        Err(e) => e,
+    };
+
+    match err.downcast_ref::<DecodeError>() {
+        Some(e) => e.path.clone(),
+        None => "unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{locate_field, verify};
+    use crate::bin::StringEncoding;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_fixtures_round_trip() {
+        let fixtures_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+
+        let mut num_fixtures = 0;
+        for entry in fs::read_dir(fixtures_dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|s| s.to_str()) != Some("kfm") {
+                continue;
+            }
+
+            let bytes = fs::read(&path).unwrap();
+            if let Some(mismatch) = verify(&bytes, StringEncoding::default()).unwrap() {
+                panic!("{:?}: {}", path, mismatch);
+            }
+            num_fixtures += 1;
+        }
+        assert!(num_fixtures > 0);
+    }
+
+    #[test]
+    fn test_text_string_encoding() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v2_2_0_0a.kfm");
+        let text = fs::read_to_string(path).unwrap();
+
+        // `ö` is a single byte in Windows-1252, and not valid UTF-8 on its own
+        let parts: Vec<_> = text.split("Accumulation_Root").collect();
+        assert_eq!(2, parts.len());
+        let bytes = [
+            parts[0].as_bytes(),
+            b"Accumulation_R\xf6ot",
+            parts[1].as_bytes(),
+        ]
+        .concat();
+
+        assert!(verify(&bytes, StringEncoding::Windows1252)
+            .unwrap()
+            .is_none());
+        assert!(verify(&bytes, StringEncoding::Utf8).is_err());
+    }
+
+    #[test]
+    fn test_trailing_bytes_mismatch() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v2_2_0_0b_le.kfm");
+        let mut bytes = fs::read(path).unwrap();
+        bytes.push(0);
+
+        let mismatch = verify(&bytes, StringEncoding::default()).unwrap().unwrap();
+        assert_eq!(bytes.len() - 1, mismatch.offset);
+        assert_eq!(Some(0), mismatch.expected);
+        assert_eq!(None, mismatch.actual);
+        assert_eq!("end of file", mismatch.field);
+    }
+
+    #[test]
+    fn test_locate_field() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v2_2_0_0b_le.kfm");
+        let bytes = fs::read(path).unwrap();
+
+        let header_len = 1 + "Gamebryo KFM File Version 2.2.0.0b\n".len() + 1;
+        assert_eq!(
+            "is_little_endian",
+            locate_field(&bytes, header_len - 1, StringEncoding::default())
+        );
+
+        let model_len = 4
+            + "./../../mesh/newenemies/mech_order_darkling_1.nif".len()
+            + 4
+            + "Accumulation_Root".len();
+        let default_trans_len = 16;
+        let offset = header_len + model_len + default_trans_len - 4;
+        assert_eq!(
+            "default_trans.non_sync_duration",
+            locate_field(&bytes, offset, StringEncoding::default())
+        );
+
+        let anims_len = 4;
+        let anim_len = 4 + 4 + "./mech/mech_gunbot_m_idle.kf".len() + 4 + 4;
+        let tran_id_len = 4;
+        let offset = header_len + model_len + default_trans_len + anims_len + anim_len;
+        assert_eq!(
+            "anims[0].trans[0].type",
+            locate_field(&bytes, offset + tran_id_len, StringEncoding::default())
+        );
+    }
+}
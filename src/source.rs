@@ -1,11 +1,14 @@
-use super::bin::{Decode, ReadValueExt};
-use super::bin::{Encode, WriteValueExt};
+use super::bin::Encode;
+use super::bin::{Decode, Limits, Options, ReadValueExt, StringEncoding, TrackingReader};
+use super::float;
+use super::text;
 use anyhow::{bail, Context, Error, Result};
 use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::Path;
 
 /// Represents a source file that can be loaded and parsed from various formats.
@@ -16,53 +19,183 @@ pub struct SourceFile {
 }
 
 impl SourceFile {
-    /// Loads a `SourceFile` from a given file path, inferring the format based on the file extension.
+    /// Loads a `SourceFile` from a given file path, detecting its format from its contents.
     ///
-    /// Supported extensions include `.kfm`, `.yaml`, and `.yml`. Returns an `Err` if the file
-    /// extension is not recognized or the file cannot be read.
+    /// Supported formats are binary and text KFM, YAML, JSON and TOML. If the contents are
+    /// ambiguous, the format is inferred from the file extension instead, which may be `.kfm`,
+    /// `.yaml`, `.yml`, `.json`, or `.toml`. Returns an `Err` if the format cannot be detected
+    /// or the file cannot be read.
     pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::load_with(path, StringEncoding::default())
+    }
+
+    /// Loads a `SourceFile` like `load`, reading the strings of `.kfm` files with the given
+    /// encoding.
+    ///
+    /// The encoding is kept in the file's header, so that the file is saved with it again.
+    pub fn load_with<P>(path: P, string_encoding: StringEncoding) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let (f, _) = Self::load_detected(path, string_encoding)?;
+        Ok(f)
+    }
+
+    /// Loads a `SourceFile` like `load_with`, and returns the format that was detected.
+    pub fn load_detected<P>(
+        path: P,
+        string_encoding: StringEncoding,
+    ) -> Result<(Self, SourceFormat)>
+    where
+        P: AsRef<Path>,
+    {
+        let (f, format) = Self::load_detected_unchecked(path, string_encoding)?;
+        f.body.check_trans_ext()?;
+        Ok((f, format))
+    }
+
+    /// Loads a `SourceFile` like `load_detected`, without rejecting transitions whose `ext` does
+    /// not match their type, so that all such transitions can be reported by validation.
+    pub fn load_detected_unchecked<P>(
+        path: P,
+        string_encoding: StringEncoding,
+    ) -> Result<(Self, SourceFormat)>
     where
         P: AsRef<Path>,
     {
         let path = path.as_ref();
+        let (mut reader, limits) = open_file(path)?;
 
-        let extension = path
-            .extension()
-            .and_then(|s| s.to_str())
-            .context("extension unreadable")?;
+        let format = match SourceFormat::sniff(reader.fill_buf().context("read file")?) {
+            Some(format) => format,
+            None => SourceFormat::from_path(path).context("detect format")?,
+        };
 
-        let file = File::open(path).context("open file")?;
-        let reader = BufReader::new(file);
+        let f = Self::from_reader_unchecked(reader, format, limits, string_encoding)?;
+        Ok((f, format))
+    }
 
-        match extension {
-            "kfm" => Self::from_kfm_reader(reader),
-            "yaml" | "yml" => Self::from_yaml_reader(reader),
-            _ => bail!("unsupported extension"),
-        }
+    /// Loads a `SourceFile` like `load_with`, reading it in the given format regardless of its
+    /// contents and file extension.
+    pub fn load_as<P>(
+        path: P,
+        format: SourceFormat,
+        string_encoding: StringEncoding,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let (reader, limits) = open_file(path.as_ref())?;
+        Self::from_reader_with(reader, format, limits, string_encoding)
     }
 
     /// Saves a `SourceFile` to a given file path, inferring the format based on the file extension.
     ///
-    /// Supported extensions include `.kfm`, `.yaml`, and `.yml`. Returns an `Err` if the file
-    /// extension is not recognized or the file cannot be written.
+    /// Supported extensions include `.kfm`, `.yaml`, `.yml`, `.json`, and `.toml`. A `.kfm`
+    /// file is written in text format if the file's header says so. Returns an `Err` if the
+    /// file extension is not recognized or the file cannot be written.
     pub fn save<P>(&self, path: P) -> Result<()>
     where
         P: AsRef<Path>,
     {
         let path = path.as_ref();
+        self.save_as(path, SourceFormat::from_path(path)?)
+    }
 
-        let extension = path
-            .extension()
-            .and_then(|s| s.to_str())
-            .context("extension unreadable")?;
-
+    /// Saves a `SourceFile` like `save`, writing it in the given format regardless of the file
+    /// extension.
+    pub fn save_as<P>(&self, path: P, format: SourceFormat) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
         let file = File::create(path).context("create file")?;
-        let writer = BufWriter::new(file);
+        let mut writer = BufWriter::new(file);
+
+        self.to_writer(&mut writer, format)?;
+        writer.flush().context("write file")
+    }
+
+    /// Creates a `SourceFile` from a reader in the given format, enforcing the given decode
+    /// limits and reading the strings of binary and text `.kfm` data with the given encoding.
+    ///
+    /// Whether `.kfm` data is in binary or text format is determined from its header.
+    pub fn from_reader_with<R>(
+        reader: R,
+        format: SourceFormat,
+        limits: Limits,
+        string_encoding: StringEncoding,
+    ) -> Result<Self>
+    where
+        R: BufRead,
+    {
+        let f = Self::from_reader_unchecked(reader, format, limits, string_encoding)?;
+        f.body.check_trans_ext()?;
+        Ok(f)
+    }
+
+    /// Creates a `SourceFile` from a reader like `from_reader_with`, without rejecting
+    /// transitions whose `ext` does not match their type.
+    pub fn from_reader_unchecked<R>(
+        mut reader: R,
+        format: SourceFormat,
+        limits: Limits,
+        string_encoding: StringEncoding,
+    ) -> Result<Self>
+    where
+        R: BufRead,
+    {
+        match format {
+            SourceFormat::Kfm => {
+                if text::is_text_kfm(reader.fill_buf().context("read input")?) {
+                    text::decode_source_file(reader, string_encoding)
+                } else {
+                    Self::from_kfm_reader_with(reader, limits, string_encoding)
+                }
+            }
+            SourceFormat::Yaml => Ok(serde_yaml::from_reader(reader)?),
+            SourceFormat::Json => Ok(serde_json::from_reader(reader)?),
+            SourceFormat::Toml => {
+                let mut s = String::new();
+                reader.read_to_string(&mut s)?;
+                Ok(toml::from_str(&s)?)
+            }
+        }
+    }
+
+    /// Creates a `SourceFile` from a reader like `from_reader_with`, detecting the format from
+    /// the contents, and returns the format that was detected.
+    pub fn from_reader_detected<R>(
+        mut reader: R,
+        limits: Limits,
+        string_encoding: StringEncoding,
+    ) -> Result<(Self, SourceFormat)>
+    where
+        R: BufRead,
+    {
+        let Some(format) = SourceFormat::sniff(reader.fill_buf().context("read input")?) else {
+            bail!("unrecognized format");
+        };
+
+        let f = Self::from_reader_with(reader, format, limits, string_encoding)?;
+        Ok((f, format))
+    }
 
-        match extension {
-            "kfm" => self.to_kfm_writer(writer),
-            "yaml" | "yml" => self.to_yaml_writer(writer),
-            _ => bail!("unsupported extension"),
+    /// Writes the `SourceFile` data to the writer in the given format.
+    ///
+    /// `.kfm` data is written in text format if the file's header says so.
+    pub fn to_writer<W>(&self, writer: W, format: SourceFormat) -> Result<()>
+    where
+        W: Write,
+    {
+        match format {
+            SourceFormat::Kfm if self.header.is_text => self.to_kfm_text_writer(writer),
+            SourceFormat::Kfm => self.to_kfm_writer(writer),
+            SourceFormat::Yaml => self.to_yaml_writer(writer),
+            SourceFormat::Json => self.to_json_writer(writer),
+            SourceFormat::Toml => self.to_toml_writer(writer),
         }
     }
 
@@ -70,25 +203,66 @@ impl SourceFile {
     ///
     /// Reads the data from the provided reader and parses it according to the KFM format,
     /// respecting the file's endianness.
-    pub fn from_kfm_reader<R>(mut reader: R) -> Result<Self>
+    pub fn from_kfm_reader<R>(reader: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        Self::from_kfm_reader_with(reader, Limits::default(), StringEncoding::default())
+    }
+
+    /// Creates a `SourceFile` from a KFM format reader, enforcing the given decode limits and
+    /// reading strings with the given encoding.
+    pub fn from_kfm_reader_with<R>(
+        reader: R,
+        limits: Limits,
+        string_encoding: StringEncoding,
+    ) -> Result<Self>
     where
         R: Read,
     {
-        let header = decode_source_file_header(&mut reader).context("read header")?;
+        let mut reader = TrackingReader::new(reader, limits.max_total_bytes);
+
+        let header =
+            decode_source_file_header(&mut reader, string_encoding).context("read header")?;
+
+        let opts = Options {
+            version: header.format_version,
+            limits,
+            string_encoding,
+        };
 
         let body = if header.is_little_endian {
             reader
-                .read_value::<SourceFileBody, LittleEndian>()
+                .read_value::<SourceFileBody, LittleEndian>(&opts)
                 .context("read body")?
         } else {
             reader
-                .read_value::<SourceFileBody, BigEndian>()
+                .read_value::<SourceFileBody, BigEndian>(&opts)
                 .context("read body")?
         };
 
         Ok(Self { header, body })
     }
 
+    /// Creates a `SourceFile` from a KFM text format reader.
+    pub fn from_kfm_text_reader<R>(reader: R) -> Result<Self>
+    where
+        R: BufRead,
+    {
+        Self::from_kfm_text_reader_with(reader, StringEncoding::default())
+    }
+
+    /// Creates a `SourceFile` from a KFM text format reader, reading its lines with the given
+    /// encoding.
+    pub fn from_kfm_text_reader_with<R>(reader: R, string_encoding: StringEncoding) -> Result<Self>
+    where
+        R: BufRead,
+    {
+        let f = text::decode_source_file(reader, string_encoding)?;
+        f.body.check_trans_ext()?;
+        Ok(f)
+    }
+
     /// Creates a `SourceFile` from a YAML format reader.
     ///
     /// Parses the YAML data from the provided reader into the `SourceFile` structure.
@@ -96,353 +270,533 @@ impl SourceFile {
     where
         R: Read,
     {
-        let f: Self = serde_yaml::from_reader(reader)?;
-        Ok(f)
+        Self::from_text_format_reader(reader, SourceFormat::Yaml)
+    }
+
+    /// Creates a `SourceFile` from a JSON format reader.
+    pub fn from_json_reader<R>(reader: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        Self::from_text_format_reader(reader, SourceFormat::Json)
+    }
+
+    /// Creates a `SourceFile` from a TOML format reader.
+    pub fn from_toml_reader<R>(reader: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        Self::from_text_format_reader(reader, SourceFormat::Toml)
+    }
+
+    fn from_text_format_reader<R>(reader: R, format: SourceFormat) -> Result<Self>
+    where
+        R: Read,
+    {
+        // Limits and string encodings only apply to `.kfm` data
+        let reader = BufReader::new(reader);
+        Self::from_reader_with(reader, format, Limits::default(), StringEncoding::default())
     }
 
     /// Writes the `SourceFile` data in KFM format to the given writer.
     ///
-    /// The encoding respects the endianness specified in the file's header.
+    /// The encoding respects the endianness and string encoding specified in the file's header.
     pub fn to_kfm_writer<W>(&self, mut writer: W) -> Result<()>
     where
         W: Write,
     {
         encode_source_file_header(&mut writer, &self.header).context("encode header")?;
 
+        let opts = Options {
+            version: self.header.format_version,
+            string_encoding: self.header.string_encoding,
+            ..Default::default()
+        };
+
         if self.header.is_little_endian {
             self.body
-                .encode::<_, LittleEndian>(&mut writer)
+                .encode::<_, LittleEndian>(&mut writer, &opts)
                 .context("encode body")?;
         } else {
             self.body
-                .encode::<_, BigEndian>(&mut writer)
+                .encode::<_, BigEndian>(&mut writer, &opts)
                 .context("encode body")?;
         }
 
         Ok(())
     }
 
+    /// Writes the `SourceFile` data in KFM text format to the given writer.
+    pub fn to_kfm_text_writer<W>(&self, mut writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        self.body.check_trans_ext()?;
+        text::encode_source_file(&mut writer, self)
+    }
+
     /// Writes the `SourceFile` data in YAML format to the writer.
     pub fn to_yaml_writer<W>(&self, writer: W) -> Result<()>
     where
         W: Write,
     {
+        self.body.check_trans_ext()?;
         serde_yaml::to_writer(writer, self)?;
         Ok(())
     }
+
+    /// Writes the `SourceFile` data in pretty-printed JSON format to the writer.
+    ///
+    /// Fields are written in declaration order, as in YAML and TOML, so that the output diffs
+    /// well. Non-finite numbers are written as strings, since JSON cannot represent them.
+    pub fn to_json_writer<W>(&self, mut writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        self.body.check_trans_ext()?;
+        let value = float::to_json_value(self)?;
+        serde_json::to_writer_pretty(&mut writer, &value)?;
+        writeln!(writer)?;
+        Ok(())
+    }
+
+    /// Writes the `SourceFile` data in TOML format to the writer.
+    ///
+    /// Animations and their transitions are written as nested arrays of tables.
+    pub fn to_toml_writer<W>(&self, mut writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        self.body.check_trans_ext()?;
+        let value = float::to_toml_value(self)?;
+        let s = toml::to_string(&value)?;
+        writer.write_all(s.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Formats that a `SourceFile` can be read from and written to.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum SourceFormat {
+    /// KFM, in either binary or text format.
+    Kfm,
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl SourceFormat {
+    /// Detects the format of a file from the start of its contents.
+    ///
+    /// KFM files are recognized by their header. YAML, JSON and TOML files are told apart by
+    /// their first line that is neither blank nor a comment. Returns `None` if the contents are
+    /// ambiguous.
+    pub fn sniff(buf: &[u8]) -> Option<Self> {
+        // Both binary and text KFM files start with the version byte and the magic
+        if buf
+            .get(1..)
+            .is_some_and(|b| b.starts_with(KFM_MAGIC_PREFIX))
+        {
+            return Some(Self::Kfm);
+        }
+
+        // The buffer may end in the middle of a character
+        let text = match std::str::from_utf8(buf) {
+            Ok(s) => s,
+            Err(e) => std::str::from_utf8(&buf[..e.valid_up_to()]).ok()?,
+        };
+        let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+
+        let line = text
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with('#'))?;
+
+        if line.starts_with('{') {
+            return Some(Self::Json);
+        }
+        // Source files are objects in JSON, so a bracket starts a TOML table
+        if line.starts_with('[') {
+            return Some(Self::Toml);
+        }
+        if line == "---" || line.starts_with("%YAML") {
+            return Some(Self::Yaml);
+        }
+
+        let key_len = line
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(line.len());
+        if key_len == 0 {
+            return None;
+        }
+        match line[key_len..].trim_start().chars().next() {
+            Some(':') => Some(Self::Yaml),
+            Some('=' | '.') => Some(Self::Toml),
+            _ => None,
+        }
+    }
+
+    /// Returns the format of files with the given extension, if it is supported.
+    ///
+    /// Extensions are matched regardless of case.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "kfm" => Some(Self::Kfm),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "json" => Some(Self::Json),
+            "toml" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+
+    /// Returns the format of the file at the given path, inferred from its extension.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|s| s.to_str())
+            .context("extension unreadable")?;
+
+        match Self::from_extension(extension) {
+            Some(format) => Ok(format),
+            None => bail!("unsupported extension"),
+        }
+    }
+
+    /// Returns the extension of files in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Kfm => "kfm",
+            Self::Yaml => "yaml",
+            Self::Json => "json",
+            Self::Toml => "toml",
+        }
+    }
+}
+
+impl fmt::Display for SourceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// Opens a file for reading, with decode limits that allow no more than its length.
+fn open_file(path: &Path) -> Result<(BufReader<File>, Limits)> {
+    let file = File::open(path).context("open file")?;
+    let file_len = file.metadata().context("read file metadata")?.len();
+
+    // The whole file is the remaining input
+    let default_limits = Limits::default();
+    let limits = Limits {
+        max_total_bytes: file_len.min(default_limits.max_total_bytes),
+        ..default_limits
+    };
+
+    Ok((BufReader::new(file), limits))
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SourceFileHeader {
     pub version: u8,
+
+    #[serde(default)]
+    pub format_version: FormatVersion,
+
     pub is_little_endian: bool,
+
+    #[serde(default)]
+    pub is_text: bool,
+
+    #[serde(default)]
+    pub string_encoding: StringEncoding,
+}
+
+impl SourceFileHeader {
+    /// Checks that the byte order can be stored in binary `.kfm` files of the format version.
+    pub fn check_endianness(&self) -> Result<()> {
+        if !self.format_version.has_endianness() && !self.is_little_endian {
+            bail!(
+                "version `{:?}` only supports little-endian",
+                self.format_version
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Known revisions of the KFM format.
+///
+/// Each revision is identified by the magic string in the file header, which ends in `b` for
+/// binary files and in `a` for text files. Revisions of the binary format differ in
+/// which fields are present in the file body:
+///
+/// - `1.2.4b` has no endianness flag (always little-endian), no animation `index` and no
+///   layer groups.
+/// - `2.0.0.0b` adds the endianness flag and the animation `index`.
+/// - `2.1.0.0b` and `2.2.0.0b` add layer groups.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy, Default)]
+pub enum FormatVersion {
+    #[serde(rename = "1.2.4b")]
+    V1_2_4b,
+
+    #[serde(rename = "2.0.0.0b")]
+    V2_0_0_0b,
+
+    #[serde(rename = "2.1.0.0b")]
+    V2_1_0_0b,
+
+    #[default]
+    #[serde(rename = "2.2.0.0b")]
+    V2_2_0_0b,
+}
+
+impl FormatVersion {
+    /// All known revisions, from oldest to newest.
+    pub const ALL: [Self; 4] = [
+        Self::V1_2_4b,
+        Self::V2_0_0_0b,
+        Self::V2_1_0_0b,
+        Self::V2_2_0_0b,
+    ];
+
+    /// Returns the string expected in the header of `.kfm` files of this revision.
+    pub fn magic(self) -> &'static str {
+        match self {
+            Self::V1_2_4b => "Gamebryo KFM File Version 1.2.4b\n",
+            Self::V2_0_0_0b => "Gamebryo KFM File Version 2.0.0.0b\n",
+            Self::V2_1_0_0b => "Gamebryo KFM File Version 2.1.0.0b\n",
+            Self::V2_2_0_0b => "Gamebryo KFM File Version 2.2.0.0b\n",
+        }
+    }
+
+    /// Returns the string expected in the header of text `.kfm` files of this revision.
+    pub fn text_magic(self) -> &'static str {
+        match self {
+            Self::V1_2_4b => "Gamebryo KFM File Version 1.2.4a\n",
+            Self::V2_0_0_0b => "Gamebryo KFM File Version 2.0.0.0a\n",
+            Self::V2_1_0_0b => "Gamebryo KFM File Version 2.1.0.0a\n",
+            Self::V2_2_0_0b => "Gamebryo KFM File Version 2.2.0.0a\n",
+        }
+    }
+
+    /// Returns the revision whose header string is `magic`, if any.
+    pub fn from_magic(magic: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|v| v.magic() == magic)
+    }
+
+    /// Returns the revision whose text header string is `magic`, if any.
+    pub fn from_text_magic(magic: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|v| v.text_magic() == magic)
+    }
+
+    /// Whether the header stores an endianness flag.
+    pub fn has_endianness(self) -> bool {
+        self >= Self::V2_0_0_0b
+    }
+
+    /// Whether animations store the index of their sequence.
+    pub fn has_anim_index(self) -> bool {
+        self >= Self::V2_0_0_0b
+    }
+
+    /// Whether the body stores layer groups.
+    pub fn has_layer_groups(self) -> bool {
+        self >= Self::V2_1_0_0b
+    }
 }
 
-/// Expected string in the header of all `.kfm` files.
-const EXPECTED_MAGIC: &str = "Gamebryo KFM File Version 2.2.0.0b\n";
+/// Maximum length of the magic string in the header of `.kfm` files.
+const MAX_MAGIC_LEN: usize = 64;
+
+/// Start of the magic of all revisions, in both binary and text format.
+const KFM_MAGIC_PREFIX: &[u8] = b"Gamebryo KFM File Version ";
 
 fn encode_source_file_header<W>(writer: &mut W, header: &SourceFileHeader) -> Result<()>
 where
     W: Write,
 {
+    let format_version = header.format_version;
+
     writer.write_u8(header.version).context("write `version`")?;
-    writer.write_all(EXPECTED_MAGIC.as_bytes())?;
-    writer.write_u8(1).context("write `is_little_endian`")?;
+    writer.write_all(format_version.magic().as_bytes())?;
+
+    header.check_endianness()?;
+    if format_version.has_endianness() {
+        writer
+            .write_u8(header.is_little_endian as u8)
+            .context("write `is_little_endian`")?;
+    }
 
     Ok(())
 }
 
-fn decode_source_file_header<R>(reader: &mut R) -> Result<SourceFileHeader>
+fn decode_source_file_header<R>(
+    reader: &mut TrackingReader<R>,
+    string_encoding: StringEncoding,
+) -> Result<SourceFileHeader>
 where
     R: Read,
 {
-    let version = reader.read_u8().context("read `version`")?;
-
-    let magic = {
-        let mut buf = vec![0u8; EXPECTED_MAGIC.len()];
-        reader.read_exact(&mut buf)?;
-        String::from_utf8(buf)?
-    };
-    if magic != EXPECTED_MAGIC {
-        bail!("unexpected `magic`: `{}`", magic);
-    }
+    let version = reader.read_field_with("version", |r| Ok(r.read_u8()?))?;
+
+    let format_version = reader.read_field_with("magic", |r| {
+        let mut buf = Vec::new();
+        loop {
+            let b = r.read_u8()?;
+            buf.push(b);
+            if b == b'\n' {
+                break;
+            }
+            if buf.len() >= MAX_MAGIC_LEN {
+                bail!("unterminated `magic`");
+            }
+        }
+        let magic = String::from_utf8(buf)?;
 
-    let is_little_endian = match reader.read_u8().context("read `is_little_endian`")? {
-        0 => false,
-        1 => true,
-        v => bail!("unexpected `is_little_endian`: {}", v),
+        match FormatVersion::from_magic(&magic) {
+            Some(v) => Ok(v),
+            None => bail!("unexpected `magic`: `{}`", magic),
+        }
+    })?;
+
+    let is_little_endian = if format_version.has_endianness() {
+        reader.read_field_with("is_little_endian", |r| match r.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => bail!("unexpected `is_little_endian`: {}", v),
+        })?
+    } else {
+        true
     };
 
     Ok(SourceFileHeader {
         version,
+        format_version,
         is_little_endian,
+        is_text: false,
+        string_encoding,
     })
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Encode, Decode)]
 pub struct SourceFileBody {
     pub model: Model,
     pub default_trans: DefaultTransitions,
     pub anims: Vec<Animation>,
+    #[kfm(since = V2_1_0_0b)]
     pub layer_groups: Vec<LayerGroup>,
 }
 
-impl Encode for SourceFileBody {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
-    where
-        W: Write,
-        O: ByteOrder,
-    {
-        writer
-            .write_value::<_, O>(&self.model)
-            .context("write `model`")?;
-
-        writer
-            .write_value::<_, O>(&self.default_trans)
-            .context("write `default_trans`")?;
-
-        writer
-            .write_value::<_, O>(self.anims.as_slice())
-            .context("write `anims`")?;
-
-        writer
-            .write_value::<_, O>(self.layer_groups.as_slice())
-            .context("write `layer_groups`")?;
-
+impl SourceFileBody {
+    /// Checks the `ext` of all transitions with `Transition::check_ext`.
+    pub fn check_trans_ext(&self) -> Result<()> {
+        for anim in self.anims.iter() {
+            for tran in anim.trans.iter() {
+                tran.check_ext()
+                    .with_context(|| format!("anim {}, trans {}", anim.id, tran.id))?;
+            }
+        }
         Ok(())
     }
 }
 
-impl Decode for SourceFileBody {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
-    where
-        R: Read,
-        O: ByteOrder,
-    {
-        let model = reader.read_value::<Model, O>().context("read `model`")?;
-
-        let default_trans = reader
-            .read_value::<DefaultTransitions, O>()
-            .context("read `default_trans`")?;
-
-        let anims = reader
-            .read_value::<Vec<Animation>, O>()
-            .context("read `anims`")?;
-
-        let layer_groups = reader
-            .read_value::<Vec<LayerGroup>, O>()
-            .context("read `layer_groups`")?;
-
-        Ok(Self {
-            model,
-            default_trans,
-            anims,
-            layer_groups,
-        })
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Encode, Decode)]
 pub struct Model {
     pub path: String,
     pub root: String,
 }
 
-impl Encode for Model {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
-    where
-        W: Write,
-        O: ByteOrder,
-    {
-        writer
-            .write_value::<_, O>(&self.path)
-            .context("write `path`")?;
-
-        writer
-            .write_value::<_, O>(&self.root)
-            .context("write `root`")?;
-
-        Ok(())
-    }
-}
-
-impl Decode for Model {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
-    where
-        R: Read,
-        O: ByteOrder,
-    {
-        let path = reader.read_value::<_, O>().context("read `path`")?;
-        let root = reader.read_value::<_, O>().context("read `root`")?;
-
-        Ok(Self { path, root })
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Encode, Decode)]
 pub struct DefaultTransitions {
+    #[kfm(order = 0)]
     pub sync_type: TransitionType,
+    #[serde(deserialize_with = "crate::float::deserialize")]
+    #[kfm(order = 2)]
     pub sync_duration: f32,
+    #[kfm(order = 1)]
     pub non_sync_type: TransitionType,
+    #[serde(deserialize_with = "crate::float::deserialize")]
+    #[kfm(order = 3)]
     pub non_sync_duration: f32,
 }
 
-impl Encode for DefaultTransitions {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
-    where
-        W: Write,
-        O: ByteOrder,
-    {
-        writer
-            .write_value::<_, O>(&self.sync_type)
-            .context("write `sync_type`")?;
-
-        writer
-            .write_value::<_, O>(&self.non_sync_type)
-            .context("write `non_sync_type`")?;
-
-        writer
-            .write_f32::<O>(self.sync_duration)
-            .context("write `sync_duration`")?;
-
-        writer
-            .write_f32::<O>(self.non_sync_duration)
-            .context("write `non_sync_duration`")?;
-
-        Ok(())
+impl DefaultTransitions {
+    /// Checks the type and duration of both default transitions, and returns an error for each
+    /// field that is invalid.
+    pub fn check(&self) -> Vec<Error> {
+        [
+            Self::check_type("sync_type", self.sync_type),
+            Self::check_duration("sync_duration", self.sync_duration),
+            Self::check_type("non_sync_type", self.non_sync_type),
+            Self::check_duration("non_sync_duration", self.non_sync_duration),
+        ]
+        .into_iter()
+        .filter_map(Result::err)
+        .collect()
     }
-}
-
-impl Decode for DefaultTransitions {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
-    where
-        R: Read,
-        O: ByteOrder,
-    {
-        let sync_type = reader.read_value::<_, O>().context("read `sync_type`")?;
-
-        let non_sync_type = reader
-            .read_value::<_, O>()
-            .context("read `non_sync_type`")?;
 
-        let sync_duration = reader.read_f32::<O>().context("read `sync_duration`")?;
-
-        let non_sync_duration = reader.read_f32::<O>().context("read `non_sync_duration`")?;
+    /// Checks that a default transition can be of the type, which must be a blend, morph or
+    /// crossfade. Other types would refer to the defaults or need chain animations.
+    pub fn check_type(name: &str, type_: TransitionType) -> Result<()> {
+        match type_ {
+            TransitionType::Blend | TransitionType::Morph | TransitionType::Crossfade => Ok(()),
+            _ => bail!("`{}` cannot be `{:?}`", name, type_),
+        }
+    }
 
-        Ok(Self {
-            sync_type,
-            sync_duration,
-            non_sync_type,
-            non_sync_duration,
-        })
+    /// Checks that a default transition duration is finite and non-negative.
+    pub fn check_duration(name: &str, duration: f32) -> Result<()> {
+        if !duration.is_finite() || duration < 0.0 {
+            bail!(
+                "`{}` must be finite and non-negative, not `{:?}`",
+                name,
+                duration
+            );
+        }
+        Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Encode, Decode)]
 pub struct Animation {
     pub id: u32,
     pub path: String,
+    #[kfm(since = V2_0_0_0b)]
     pub index: u32,
     pub trans: Vec<Transition>,
 }
 
-impl Encode for Animation {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
-    where
-        W: Write,
-        O: ByteOrder,
-    {
-        writer.write_u32::<O>(self.id).context("write `id`")?;
-
-        writer
-            .write_value::<_, O>(&self.path)
-            .context("write `path`")?;
-
-        writer.write_u32::<O>(self.index).context("write `index`")?;
-
-        writer
-            .write_value::<_, O>(self.trans.as_slice())
-            .context("write `trans`")?;
-
-        Ok(())
-    }
-}
-
-impl Decode for Animation {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
-    where
-        R: Read,
-        O: ByteOrder,
-    {
-        let id = reader.read_u32::<O>().context("read `id`")?;
-        let path = reader.read_value::<_, O>().context("read `path`")?;
-        let index = reader.read_u32::<O>().context("read `index`")?;
-        let trans = reader
-            .read_value::<Vec<Transition>, O>()
-            .context("read `trans`")?;
-
-        Ok(Self {
-            id,
-            path,
-            index,
-            trans,
-        })
+impl Animation {
+    /// Returns the stem of the animation's `.kf` file, which names the animation.
+    pub fn kf_stem(&self) -> String {
+        let path = self.path.replace("\\", "/");
+        Path::new(&path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default()
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Encode, Decode)]
+#[kfm(check = "Self::check_ext")]
 pub struct Transition {
     pub id: u32,
 
     #[serde(rename = "type")]
+    #[kfm(rename = "type")]
     pub type_: TransitionType,
 
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[kfm(if = "type_.has_ext()")]
     pub ext: Option<TransitionExt>,
 }
 
-impl Encode for Transition {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
-    where
-        W: Write,
-        O: ByteOrder,
-    {
-        writer.write_u32::<O>(self.id).context("write `id`")?;
-
-        writer
-            .write_value::<_, O>(&self.type_)
-            .context("write `type`")?;
-
-        if let Some(ext) = &self.ext {
-            writer.write_value::<_, O>(ext).context("write `ext`")?;
-        }
-
-        Ok(())
-    }
-}
-
-impl Decode for Transition {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
-    where
-        R: Read,
-        O: ByteOrder,
-    {
-        let id = reader.read_u32::<O>().context("read `id`")?;
-
-        let type_ = reader
-            .read_value::<TransitionType, O>()
-            .context("read `type`")?;
-
-        let ext = match type_ {
-            TransitionType::DefaultSync | TransitionType::DefaultNonSync => None,
-            _ => Some(
-                reader
-                    .read_value::<TransitionExt, O>()
-                    .context("read `ext`")?,
-            ),
-        };
-
-        Ok(Self { id, type_, ext })
+impl Transition {
+    /// Checks that `ext` is present exactly when the transition's type has one.
+    ///
+    /// KFM files store `ext` only for types other than the default ones, so a transition that
+    /// breaks this cannot be encoded and read back.
+    pub fn check_ext(&self) -> Result<()> {
+        self.type_.check_ext(self.ext.as_ref())
     }
 }
 
@@ -457,8 +811,26 @@ pub enum TransitionType {
     DefaultNonSync,
 }
 
+impl TransitionType {
+    /// Returns whether transitions of this type have a `TransitionExt`.
+    ///
+    /// Default transitions take their duration from `DefaultTransitions` instead.
+    pub fn has_ext(self) -> bool {
+        !matches!(self, Self::DefaultSync | Self::DefaultNonSync)
+    }
+
+    /// Checks that `ext` is present exactly when transitions of this type have one.
+    pub fn check_ext(self, ext: Option<&TransitionExt>) -> Result<()> {
+        match (ext, self.has_ext()) {
+            (Some(_), false) => bail!("`ext` is not stored for type `{:?}`", self),
+            (None, true) => bail!("`ext` is required for type `{:?}`", self),
+            _ => Ok(()),
+        }
+    }
+}
+
 impl Encode for TransitionType {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
+    fn encode<W, O>(&self, writer: &mut W, _opts: &Options) -> Result<()>
     where
         W: Write,
         O: ByteOrder,
@@ -481,12 +853,12 @@ impl Encode for TransitionType {
 }
 
 impl Decode for TransitionType {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
+    fn decode<R, O>(reader: &mut R, opts: &Options) -> Result<Self>
     where
-        R: Read,
+        R: ReadValueExt,
         O: ByteOrder,
     {
-        let type_code = reader.read_u32::<O>().context("read `type_code`")?;
+        let type_code = reader.read_value::<u32, O>(opts)?;
 
         let type_ = match type_code {
             0 => Self::Blend,
@@ -502,245 +874,47 @@ impl Decode for TransitionType {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Encode, Decode)]
 pub struct TransitionExt {
+    #[serde(deserialize_with = "crate::float::deserialize")]
     pub duration: f32,
     pub intermediate_anims: Vec<IntermediateAnimation>,
     pub chain_anims: Vec<ChainAnimation>,
 }
 
-impl Encode for TransitionExt {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
-    where
-        W: Write,
-        O: ByteOrder,
-    {
-        writer
-            .write_f32::<O>(self.duration)
-            .context("write `duration`")?;
-
-        writer
-            .write_value::<_, O>(self.intermediate_anims.as_slice())
-            .context("write `intermediate_anims`")?;
-
-        writer
-            .write_value::<_, O>(self.chain_anims.as_slice())
-            .context("write `chain_anims`")?;
-
-        Ok(())
-    }
-}
-
-impl Decode for TransitionExt {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
-    where
-        R: Read,
-        O: ByteOrder,
-    {
-        let duration = reader.read_f32::<O>().context("read `duration`")?;
-
-        let intermediate_anims = reader
-            .read_value::<Vec<IntermediateAnimation>, O>()
-            .context("read `intermediate_anims`")?;
-
-        let chain_anims = reader
-            .read_value::<Vec<ChainAnimation>, O>()
-            .context("read `chain_anims`")?;
-
-        Ok(Self {
-            duration,
-            intermediate_anims,
-            chain_anims,
-        })
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Encode, Decode)]
 pub struct Layer {
     pub id: u32,
     pub priority: i32,
+    #[serde(deserialize_with = "crate::float::deserialize")]
     pub weight: f32,
+    #[serde(deserialize_with = "crate::float::deserialize")]
     pub ease_in_time: f32,
+    #[serde(deserialize_with = "crate::float::deserialize")]
     pub ease_out_time: f32,
     pub sync_id: u32,
 }
 
-impl Encode for Layer {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
-    where
-        W: Write,
-        O: ByteOrder,
-    {
-        writer.write_u32::<O>(self.id).context("write `id`")?;
-
-        writer
-            .write_i32::<O>(self.priority)
-            .context("write `priority`")?;
-
-        writer
-            .write_f32::<O>(self.weight)
-            .context("write `weight`")?;
-
-        writer
-            .write_f32::<O>(self.ease_in_time)
-            .context("write `ease_in_time`")?;
-
-        writer
-            .write_f32::<O>(self.ease_out_time)
-            .context("write `ease_out_time`")?;
-
-        writer
-            .write_u32::<O>(self.sync_id)
-            .context("write `sync_id`")?;
-
-        Ok(())
-    }
-}
-
-impl Decode for Layer {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
-    where
-        R: Read,
-        O: ByteOrder,
-    {
-        let id = reader.read_u32::<O>().context("read `id`")?;
-        let priority = reader.read_i32::<O>().context("read `priority`")?;
-        let weight = reader.read_f32::<O>().context("read `weight`")?;
-        let ease_in_time = reader.read_f32::<O>().context("read `ease_in_time`")?;
-        let ease_out_time = reader.read_f32::<O>().context("read `ease_out_time`")?;
-        let sync_id = reader.read_u32::<O>().context("read `sync_id`")?;
-
-        Ok(Self {
-            id,
-            priority,
-            weight,
-            ease_in_time,
-            ease_out_time,
-            sync_id,
-        })
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Encode, Decode)]
 pub struct LayerGroup {
     pub id: u32,
     pub name: String,
     pub layers: Vec<Layer>,
 }
 
-impl Decode for LayerGroup {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
-    where
-        R: Read,
-        O: ByteOrder,
-    {
-        let id = reader.read_u32::<O>().context("read `id`")?;
-
-        let name = reader.read_value::<_, O>().context("read `name`")?;
-
-        let layers = reader
-            .read_value::<Vec<Layer>, O>()
-            .context("read `layers`")?;
-
-        Ok(Self { id, name, layers })
-    }
-}
-
-impl Encode for LayerGroup {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
-    where
-        W: Write,
-        O: ByteOrder,
-    {
-        writer.write_u32::<O>(self.id).context("write `id`")?;
-
-        writer
-            .write_value::<_, O>(&self.name)
-            .context("write `name`")?;
-
-        writer
-            .write_value::<_, O>(self.layers.as_slice())
-            .context("write `layers`")?;
-
-        Ok(())
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Encode, Decode)]
 pub struct IntermediateAnimation {
     pub start_key: String,
     pub target_key: String,
 }
 
-impl Encode for IntermediateAnimation {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
-    where
-        W: Write,
-        O: ByteOrder,
-    {
-        writer
-            .write_value::<_, O>(&self.start_key)
-            .context("write `start_key`")?;
-
-        writer
-            .write_value::<_, O>(&self.target_key)
-            .context("write `target_key`")?;
-
-        Ok(())
-    }
-}
-
-impl Decode for IntermediateAnimation {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
-    where
-        R: Read,
-        O: ByteOrder,
-    {
-        let start_key = reader.read_value::<_, O>().context("read `start_key`")?;
-        let target_key = reader.read_value::<_, O>().context("read `target_key`")?;
-
-        Ok(Self {
-            start_key,
-            target_key,
-        })
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Encode, Decode)]
 pub struct ChainAnimation {
     pub id: u32,
+    #[serde(deserialize_with = "crate::float::deserialize")]
     pub duration: f32,
 }
 
-impl Encode for ChainAnimation {
-    fn encode<W, O>(&self, writer: &mut W) -> Result<()>
-    where
-        W: Write,
-        O: ByteOrder,
-    {
-        writer.write_u32::<O>(self.id).context("write `id`")?;
-
-        writer
-            .write_f32::<O>(self.duration)
-            .context("write `duration`")?;
-
-        Ok(())
-    }
-}
-
-impl Decode for ChainAnimation {
-    fn decode<R, O>(reader: &mut R) -> Result<Self>
-    where
-        R: Read,
-        O: ByteOrder,
-    {
-        let id = reader.read_u32::<O>().context("read `id`")?;
-        let duration = reader.read_f32::<O>().context("read `duration`")?;
-
-        Ok(Self { id, duration })
-    }
-}
-
 #[derive(Debug)]
 pub struct MappedSource {
     pub model: Model,
@@ -863,3 +1037,536 @@ impl From<(u32, MappedTransition)> for Transition {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Animation, DefaultTransitions, Model, Transition, TransitionType};
+    use super::{ChainAnimation, TransitionExt};
+    use super::{FormatVersion, Layer, LayerGroup};
+    use super::{SourceFile, SourceFileBody, SourceFileHeader, SourceFormat};
+    use crate::bin::WriteValueExt;
+    use crate::bin::{DecodeError, Limits, Options, ReadValueExt, StringEncoding, TrackingReader};
+    use byteorder::LittleEndian;
+    use std::error::Error;
+    use std::fs;
+    use std::path::Path;
+
+    fn make_source_file(format_version: FormatVersion) -> SourceFile {
+        SourceFile {
+            header: SourceFileHeader {
+                version: b';',
+                format_version,
+                is_little_endian: true,
+                is_text: false,
+                string_encoding: StringEncoding::default(),
+            },
+            body: SourceFileBody {
+                model: Model {
+                    path: "./../../mesh/newenemies/mech_order_darkling_1.nif".to_string(),
+                    root: "Accumulation_Root".to_string(),
+                },
+                default_trans: DefaultTransitions {
+                    sync_type: TransitionType::Morph,
+                    sync_duration: 0.25,
+                    non_sync_type: TransitionType::Blend,
+                    non_sync_duration: 0.25,
+                },
+                anims: vec![Animation {
+                    id: 0,
+                    path: "./mech/mech_gunbot_m_idle.kf".to_string(),
+                    index: 0,
+                    trans: vec![Transition {
+                        id: 1,
+                        type_: TransitionType::DefaultSync,
+                        ext: None,
+                    }],
+                }],
+                layer_groups: Vec::new(),
+            },
+        }
+    }
+
+    fn to_kfm_bytes(src_file: &SourceFile) -> Vec<u8> {
+        let mut buf = Vec::new();
+        src_file.to_kfm_writer(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_format_version_round_trip() {
+        for format_version in FormatVersion::ALL {
+            let expected = to_kfm_bytes(&make_source_file(format_version));
+            assert!(expected[1..].starts_with(format_version.magic().as_bytes()));
+
+            let src_file = SourceFile::from_kfm_reader(expected.as_slice()).unwrap();
+            assert_eq!(format_version, src_file.header.format_version);
+
+            let actual = to_kfm_bytes(&src_file);
+            assert_eq!(expected, actual);
+        }
+    }
+
+    #[test]
+    fn test_big_endian_round_trip() {
+        let mut src_file = make_source_file(FormatVersion::V2_2_0_0b);
+        src_file.header.is_little_endian = false;
+
+        let expected = to_kfm_bytes(&src_file);
+        let magic_len = FormatVersion::V2_2_0_0b.magic().len();
+        assert_eq!(0, expected[1 + magic_len]);
+
+        let src_file = SourceFile::from_kfm_reader(expected.as_slice()).unwrap();
+        assert!(!src_file.header.is_little_endian);
+        assert_eq!(
+            "./mech/mech_gunbot_m_idle.kf",
+            src_file.body.anims[0].path.as_str()
+        );
+
+        let actual = to_kfm_bytes(&src_file);
+        assert_eq!(expected, actual);
+
+        // `1.2.4b` has no endianness flag, which is checked before anything is written
+        let mut src_file = make_source_file(FormatVersion::V1_2_4b);
+        assert!(src_file.header.check_endianness().is_ok());
+        src_file.header.is_little_endian = false;
+        let err = src_file.header.check_endianness().unwrap_err();
+        assert_eq!(
+            "version `V1_2_4b` only supports little-endian",
+            err.to_string()
+        );
+        assert!(src_file.to_kfm_writer(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_decode_error_location() {
+        let mut bytes = to_kfm_bytes(&make_source_file(FormatVersion::V2_2_0_0b));
+
+        // Overwrite the `type` of the first transition with an unknown code
+        let offset = bytes.len() - 4 - 4;
+        bytes[offset] = 9;
+
+        let err = SourceFile::from_kfm_reader(bytes.as_slice()).unwrap_err();
+        let decode_err = err.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!(offset as u64, decode_err.offset);
+        assert_eq!("anims[0].trans[0].type", decode_err.path);
+        assert_eq!(
+            format!(
+                "read body: read `anims[0].trans[0].type` at offset {:#x}: \
+                 unknown trans `type_code`: 9",
+                offset
+            ),
+            format!("{:#}", err)
+        );
+
+        // Truncate the file in the middle of the path of the first animation
+        let path_len = 4 + "./mech/mech_gunbot_m_idle.kf".len();
+        let path_offset = bytes.len() - 4 - 4 - 4 - 4 - 4 - path_len;
+        let err = SourceFile::from_kfm_reader(&bytes[..path_offset + 10]).unwrap_err();
+        let decode_err = err.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!(path_offset as u64, decode_err.offset);
+        assert_eq!("anims[0].path", decode_err.path);
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let mut bytes = to_kfm_bytes(&make_source_file(FormatVersion::V2_2_0_0b));
+        let limits = Limits {
+            max_total_bytes: bytes.len() as u64,
+            ..Limits::default()
+        };
+
+        // Overwrite the length of `model.path`
+        let offset = 1 + FormatVersion::V2_2_0_0b.magic().len() + 1;
+        bytes[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err =
+            SourceFile::from_kfm_reader_with(bytes.as_slice(), limits, StringEncoding::default())
+                .unwrap_err();
+        let decode_err = err.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!("model.path", decode_err.path);
+        assert_eq!(
+            "string length 4294967295 exceeds limit of 4096",
+            decode_err.source().unwrap().to_string()
+        );
+
+        bytes[offset..offset + 4].copy_from_slice(&4000u32.to_le_bytes());
+        let err =
+            SourceFile::from_kfm_reader_with(bytes.as_slice(), limits, StringEncoding::default())
+                .unwrap_err();
+        let decode_err = err.downcast_ref::<DecodeError>().unwrap();
+        assert!(decode_err
+            .source()
+            .unwrap()
+            .to_string()
+            .starts_with("string length 4000 exceeds remaining input"));
+
+        // Overwrite the number of `anims`
+        let mut bytes = to_kfm_bytes(&make_source_file(FormatVersion::V2_2_0_0b));
+        let model = &make_source_file(FormatVersion::V2_2_0_0b).body.model;
+        let offset = offset + 4 + model.path.len() + 4 + model.root.len() + 16;
+        bytes[offset..offset + 4].copy_from_slice(&0x10000000u32.to_le_bytes());
+        let err = SourceFile::from_kfm_reader(bytes.as_slice()).unwrap_err();
+        let decode_err = err.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!("anims", decode_err.path);
+        assert_eq!(
+            "item count 268435456 exceeds limit of 65536",
+            decode_err.source().unwrap().to_string()
+        );
+
+        // Limit the total number of bytes
+        let limits = Limits {
+            max_total_bytes: bytes.len() as u64 - 1,
+            ..Limits::default()
+        };
+        let bytes = to_kfm_bytes(&make_source_file(FormatVersion::V2_2_0_0b));
+        assert!(SourceFile::from_kfm_reader_with(
+            bytes.as_slice(),
+            limits,
+            StringEncoding::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_decode_corrupt_input() {
+        let fixtures_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        for entry in fs::read_dir(fixtures_dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|s| s.to_str()) != Some("kfm") {
+                continue;
+            }
+            let bytes = fs::read(&path).unwrap();
+
+            // Decoding must fail cleanly, never panic
+            for len in 0..bytes.len() {
+                assert!(SourceFile::from_kfm_reader(&bytes[..len]).is_err());
+            }
+            for i in 0..bytes.len() {
+                let mut corrupt = bytes.clone();
+                corrupt[i] ^= 0xff;
+                let _ = SourceFile::from_kfm_reader(corrupt.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn test_string_encoding_round_trip() {
+        let mut src_file = make_source_file(FormatVersion::V2_2_0_0b);
+        src_file.header.string_encoding = StringEncoding::Latin1;
+        src_file.body.anims[0].path = "./mech/mech_gunbot_m_d\u{e9}j\u{e0}_vu.kf".to_string();
+
+        let bytes = to_kfm_bytes(&src_file);
+        let needle = b"d\xe9j\xe0_vu";
+        assert!(bytes.windows(needle.len()).any(|w| w == needle));
+
+        let src_file = SourceFile::from_kfm_reader_with(
+            bytes.as_slice(),
+            Limits::default(),
+            StringEncoding::Latin1,
+        )
+        .unwrap();
+        assert_eq!(StringEncoding::Latin1, src_file.header.string_encoding);
+        assert_eq!(
+            "./mech/mech_gunbot_m_d\u{e9}j\u{e0}_vu.kf",
+            src_file.body.anims[0].path
+        );
+        assert_eq!(bytes, to_kfm_bytes(&src_file));
+
+        // Strict ASCII can neither read nor write the file
+        assert!(SourceFile::from_kfm_reader_with(
+            bytes.as_slice(),
+            Limits::default(),
+            StringEncoding::Ascii
+        )
+        .is_err());
+        let mut src_file = src_file;
+        src_file.header.string_encoding = StringEncoding::Ascii;
+        assert!(src_file.to_kfm_writer(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_utf8_strings_by_default() {
+        // Multi-byte UTF-8, as written by earlier versions that only read and wrote UTF-8
+        let mut src_file = make_source_file(FormatVersion::V2_2_0_0b);
+        src_file.body.anims[0].path = "./mech/caf\u{e9}_\u{20ac}.kf".to_string();
+        let bytes = to_kfm_bytes(&src_file);
+        let needle = b"caf\xc3\xa9_\xe2\x82\xac";
+        assert!(bytes.windows(needle.len()).any(|w| w == needle));
+
+        let src_file = SourceFile::from_kfm_reader(bytes.as_slice()).unwrap();
+        assert_eq!(StringEncoding::Utf8, src_file.header.string_encoding);
+        assert_eq!("./mech/caf\u{e9}_\u{20ac}.kf", src_file.body.anims[0].path);
+        assert_eq!(bytes, to_kfm_bytes(&src_file));
+
+        // Windows-1252 must be asked for, and reads the same bytes as other characters
+        let src_file = SourceFile::from_kfm_reader_with(
+            bytes.as_slice(),
+            Limits::default(),
+            StringEncoding::Windows1252,
+        )
+        .unwrap();
+        assert_eq!(
+            "./mech/caf\u{c3}\u{a9}_\u{e2}\u{201a}\u{ac}.kf",
+            src_file.body.anims[0].path
+        );
+        assert_eq!(bytes, to_kfm_bytes(&src_file));
+    }
+
+    #[test]
+    fn test_format_version_unsupported_fields() {
+        let mut src_file = make_source_file(FormatVersion::V2_0_0_0b);
+        src_file.body.layer_groups.push(LayerGroup {
+            id: 0,
+            name: "upper_body".to_string(),
+            layers: vec![Layer {
+                id: 0,
+                priority: 1,
+                weight: 1.0,
+                ease_in_time: 0.1,
+                ease_out_time: 0.1,
+                sync_id: 0,
+            }],
+        });
+        assert!(src_file.to_kfm_writer(Vec::new()).is_err());
+
+        let mut src_file = make_source_file(FormatVersion::V1_2_4b);
+        src_file.body.anims[0].index = 1;
+        assert!(src_file.to_kfm_writer(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_derived_field_order() {
+        let default_trans = DefaultTransitions {
+            sync_type: TransitionType::Morph,
+            sync_duration: 0.5,
+            non_sync_type: TransitionType::Crossfade,
+            non_sync_duration: 0.25,
+        };
+
+        let mut buf = Vec::new();
+        buf.write_value::<_, LittleEndian>(&default_trans, &Options::default())
+            .unwrap();
+
+        let mut expected = Vec::new();
+        expected.extend(1u32.to_le_bytes());
+        expected.extend(2u32.to_le_bytes());
+        expected.extend(0.5f32.to_le_bytes());
+        expected.extend(0.25f32.to_le_bytes());
+        assert_eq!(expected, buf);
+    }
+
+    #[test]
+    fn test_derived_conditional_field() {
+        let opts = Options::default();
+        let trans = [
+            Transition {
+                id: 1,
+                type_: TransitionType::DefaultSync,
+                ext: None,
+            },
+            Transition {
+                id: 2,
+                type_: TransitionType::Blend,
+                ext: Some(TransitionExt {
+                    duration: 0.5,
+                    intermediate_anims: Vec::new(),
+                    chain_anims: Vec::new(),
+                }),
+            },
+        ];
+
+        let mut buf = Vec::new();
+        buf.write_value::<_, LittleEndian>(&trans[..], &opts)
+            .unwrap();
+        assert_eq!(4 + (4 + 4) + (4 + 4 + 4 + 4 + 4), buf.len());
+
+        let len = buf.len() as u64;
+        let mut reader = TrackingReader::new(&buf[..], len);
+        let decoded = reader
+            .read_value::<Vec<Transition>, LittleEndian>(&opts)
+            .unwrap();
+        assert!(decoded[0].ext.is_none());
+        assert_eq!(0.5, decoded[1].ext.as_ref().unwrap().duration);
+        assert_eq!(len, reader.position());
+    }
+
+    #[test]
+    fn test_inconsistent_trans_ext() {
+        let mut src_file = make_source_file(FormatVersion::V2_2_0_0b);
+        src_file.body.anims[0].trans[0].type_ = TransitionType::Blend;
+
+        let err = src_file.to_kfm_writer(Vec::new()).unwrap_err();
+        assert!(format!("{:#}", err).ends_with("`ext` is required for type `Blend`"));
+
+        let err = src_file.to_yaml_writer(Vec::new()).unwrap_err();
+        assert_eq!(
+            "anim 0, trans 1: `ext` is required for type `Blend`",
+            format!("{:#}", err)
+        );
+
+        let yaml = serde_yaml::to_string(&src_file).unwrap();
+        let err = SourceFile::from_yaml_reader(yaml.as_bytes()).unwrap_err();
+        assert_eq!(
+            "anim 0, trans 1: `ext` is required for type `Blend`",
+            format!("{:#}", err)
+        );
+
+        src_file.body.anims[0].trans[0] = Transition {
+            id: 1,
+            type_: TransitionType::DefaultNonSync,
+            ext: Some(TransitionExt {
+                duration: 0.5,
+                intermediate_anims: Vec::new(),
+                chain_anims: Vec::new(),
+            }),
+        };
+        let err = src_file.to_kfm_writer(Vec::new()).unwrap_err();
+        assert!(format!("{:#}", err).ends_with("`ext` is not stored for type `DefaultNonSync`"));
+
+        assert!(src_file.to_json_writer(Vec::new()).is_err());
+        assert!(src_file.to_toml_writer(Vec::new()).is_err());
+        assert!(src_file.to_kfm_text_writer(Vec::new()).is_err());
+
+        let json = serde_json::to_vec(&src_file).unwrap();
+        assert!(SourceFile::from_json_reader(&json[..]).is_err());
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let src_file = make_source_file(FormatVersion::V2_2_0_0b);
+
+        let mut json = Vec::new();
+        src_file.to_json_writer(&mut json).unwrap();
+        assert!(json.ends_with(b"}\n"));
+
+        // Keys keep declaration order rather than being sorted
+        let text = String::from_utf8(json.clone()).unwrap();
+        let pos = |key: &str| text.find(&format!("\"{}\"", key)).unwrap();
+        assert!(pos("header") < pos("body"));
+        assert!(pos("model") < pos("default_trans"));
+        assert!(pos("default_trans") < pos("anims"));
+
+        let decoded = SourceFile::from_json_reader(&json[..]).unwrap();
+        assert_eq!(to_kfm_bytes(&src_file), to_kfm_bytes(&decoded));
+
+        // Output is stable
+        let mut json_again = Vec::new();
+        decoded.to_json_writer(&mut json_again).unwrap();
+        assert_eq!(json, json_again);
+    }
+
+    #[test]
+    fn test_toml_round_trip() {
+        let mut src_file = make_source_file(FormatVersion::V2_2_0_0b);
+        src_file.body.anims[0].trans.push(Transition {
+            id: 2,
+            type_: TransitionType::ChainAnimation,
+            ext: Some(TransitionExt {
+                duration: f32::NAN,
+                intermediate_anims: Vec::new(),
+                chain_anims: vec![ChainAnimation {
+                    id: 1,
+                    duration: 0.1,
+                }],
+            }),
+        });
+
+        let mut toml = Vec::new();
+        src_file.to_toml_writer(&mut toml).unwrap();
+        let toml = String::from_utf8(toml).unwrap();
+        assert!(toml.contains("[[body.anims.trans]]"));
+        assert!(toml.contains("duration = 0.1\n"));
+
+        let decoded = SourceFile::from_toml_reader(toml.as_bytes()).unwrap();
+        assert_eq!(to_kfm_bytes(&src_file), to_kfm_bytes(&decoded));
+    }
+
+    #[test]
+    fn test_reader_writer_formats() {
+        let src_file = make_source_file(FormatVersion::V2_2_0_0b);
+        let expected = to_kfm_bytes(&src_file);
+
+        for format in [
+            SourceFormat::Kfm,
+            SourceFormat::Yaml,
+            SourceFormat::Json,
+            SourceFormat::Toml,
+        ] {
+            let mut buf = Vec::new();
+            src_file.to_writer(&mut buf, format).unwrap();
+
+            let decoded = SourceFile::from_reader_with(
+                &buf[..],
+                format,
+                Limits::default(),
+                StringEncoding::default(),
+            )
+            .unwrap();
+            assert_eq!(expected, to_kfm_bytes(&decoded), "{:?}", format);
+
+            let path = Path::new("file").with_extension(format.extension());
+            assert_eq!(format, SourceFormat::from_path(&path).unwrap());
+        }
+
+        assert_eq!(
+            SourceFormat::Yaml,
+            SourceFormat::from_path(Path::new("file.yml")).unwrap()
+        );
+        assert!(SourceFormat::from_path(Path::new("file.bak")).is_err());
+        assert!(SourceFormat::from_path(Path::new("file")).is_err());
+    }
+
+    #[test]
+    fn test_sniff_format() {
+        let fixtures_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        for name in ["v1_2_4b.kfm", "v2_2_0_0b_be.kfm", "v2_2_0_0a.kfm"] {
+            let bytes = fs::read(fixtures_dir.join(name)).unwrap();
+            assert_eq!(
+                Some(SourceFormat::Kfm),
+                SourceFormat::sniff(&bytes),
+                "{}",
+                name
+            );
+        }
+
+        let src_file = make_source_file(FormatVersion::V2_2_0_0b);
+        for format in [SourceFormat::Yaml, SourceFormat::Json, SourceFormat::Toml] {
+            let mut buf = Vec::new();
+            src_file.to_writer(&mut buf, format).unwrap();
+            assert_eq!(Some(format), SourceFormat::sniff(&buf));
+        }
+
+        let cases = [
+            ("# comment\n\nheader:\n", Some(SourceFormat::Yaml)),
+            ("---\n", Some(SourceFormat::Yaml)),
+            (
+                ";Gamebryo KFM File Version 2.2.0.0a\r\nmodel \"a.nif\" \"Root\"\r\n",
+                Some(SourceFormat::Kfm),
+            ),
+            ("\u{feff}  {\"header\": {}}", Some(SourceFormat::Json)),
+            ("# comment\n[[anims]]\n", Some(SourceFormat::Toml)),
+            ("header.version = 59\n", Some(SourceFormat::Toml)),
+            ("", None),
+            ("# only a comment\n", None),
+            ("- 1\n", None),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(expected, SourceFormat::sniff(text.as_bytes()), "{:?}", text);
+        }
+
+        // Multi-byte characters cut off at the end of the buffer
+        assert_eq!(
+            Some(SourceFormat::Yaml),
+            SourceFormat::sniff(&"a: \u{e9}".as_bytes()[..4])
+        );
+    }
+
+    #[test]
+    fn test_format_from_extension() {
+        assert_eq!(Some(SourceFormat::Kfm), SourceFormat::from_extension("KFM"));
+        assert_eq!(
+            Some(SourceFormat::Yaml),
+            SourceFormat::from_extension("Yml")
+        );
+        assert_eq!(None, SourceFormat::from_extension("bak"));
+    }
+}
@@ -0,0 +1,509 @@
+use crate::bin::StringEncoding;
+use crate::source::{Animation, ChainAnimation, DefaultTransitions, IntermediateAnimation};
+use crate::source::{FormatVersion, Layer, LayerGroup, Model, Transition, TransitionExt};
+use crate::source::{SourceFile, SourceFileBody, SourceFileHeader, TransitionType};
+use anyhow::{bail, Context, Error, Result};
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+/// Returns whether `buf` starts with the header of a KFM file in text format.
+pub fn is_text_kfm(buf: &[u8]) -> bool {
+    match buf.iter().position(|b| *b == b'\n') {
+        Some(i) if i > 0 => parse_magic(&buf[1..i]).is_some(),
+        _ => false,
+    }
+}
+
+/// Parses the magic that follows the `version` byte of a header line without its `\n`.
+///
+/// The magic may end in `\r`, since text files written on Windows end lines in `\r\n`.
+fn parse_magic(bytes: &[u8]) -> Option<FormatVersion> {
+    let magic = std::str::from_utf8(bytes).ok()?;
+    FormatVersion::from_text_magic(&format!("{}\n", magic.trim_end_matches('\r')))
+}
+
+/// Writes a `SourceFile` in KFM text format, encoding it with the header's string encoding.
+///
+/// Each record is written on its own line. Records that belong to an animation, transition
+/// or layer group follow it and are indented for readability.
+pub fn encode_source_file<W>(writer: &mut W, src_file: &SourceFile) -> Result<()>
+where
+    W: Write,
+{
+    let header = &src_file.header;
+    writer.write_all(&[header.version])?;
+    writer.write_all(header.format_version.text_magic().as_bytes())?;
+
+    // Records are formatted as UTF-8 first, and then encoded line by line
+    let mut buf = Vec::new();
+    encode_body(&mut buf, &src_file.body)?;
+    let text = String::from_utf8(buf)?;
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        let bytes = header
+            .string_encoding
+            .encode(line)
+            .with_context(|| format!("write line {}", i + 2))?;
+        writer.write_all(&bytes)?;
+    }
+
+    Ok(())
+}
+
+fn encode_body<W>(writer: &mut W, body: &SourceFileBody) -> Result<()>
+where
+    W: Write,
+{
+    writeln!(
+        writer,
+        "model {} {}",
+        quote(&body.model.path)?,
+        quote(&body.model.root)?
+    )?;
+
+    let default_trans = &body.default_trans;
+    writeln!(
+        writer,
+        "default_sync {} {}",
+        type_name(default_trans.sync_type),
+        default_trans.sync_duration
+    )?;
+    writeln!(
+        writer,
+        "default_non_sync {} {}",
+        type_name(default_trans.non_sync_type),
+        default_trans.non_sync_duration
+    )?;
+
+    for anim in body.anims.iter() {
+        writeln!(
+            writer,
+            "anim {} {} {}",
+            anim.id,
+            quote(&anim.path)?,
+            anim.index
+        )?;
+
+        for tran in anim.trans.iter() {
+            write!(writer, "  tran {} {}", tran.id, type_name(tran.type_))?;
+
+            let ext = match &tran.ext {
+                Some(e) => e,
+                None => {
+                    writeln!(writer)?;
+                    continue;
+                }
+            };
+            writeln!(writer, " {}", ext.duration)?;
+
+            for intermediate_anim in ext.intermediate_anims.iter() {
+                writeln!(
+                    writer,
+                    "    intermediate {} {}",
+                    quote(&intermediate_anim.start_key)?,
+                    quote(&intermediate_anim.target_key)?
+                )?;
+            }
+
+            for chain_anim in ext.chain_anims.iter() {
+                writeln!(
+                    writer,
+                    "    chain {} {}",
+                    chain_anim.id, chain_anim.duration
+                )?;
+            }
+        }
+    }
+
+    for layer_group in body.layer_groups.iter() {
+        writeln!(
+            writer,
+            "layer_group {} {}",
+            layer_group.id,
+            quote(&layer_group.name)?
+        )?;
+
+        for layer in layer_group.layers.iter() {
+            writeln!(
+                writer,
+                "  layer {} {} {} {} {} {}",
+                layer.id,
+                layer.priority,
+                layer.weight,
+                layer.ease_in_time,
+                layer.ease_out_time,
+                layer.sync_id
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a `SourceFile` in KFM text format, decoding its lines with `string_encoding`.
+///
+/// Blank lines and lines starting with `;` after the header are ignored.
+pub fn decode_source_file<R>(reader: R, string_encoding: StringEncoding) -> Result<SourceFile>
+where
+    R: BufRead,
+{
+    let mut lines = reader.split(b'\n');
+
+    let header_line = lines.next().context("missing header")??;
+    let header = decode_header(&header_line, string_encoding).context("read header")?;
+
+    let mut model = None;
+    let mut sync = None;
+    let mut non_sync = None;
+    let mut anims: Vec<Animation> = Vec::new();
+    let mut layer_groups: Vec<LayerGroup> = Vec::new();
+
+    for (i, line) in lines.enumerate() {
+        let line_num = i + 2;
+        let line = line
+            .map_err(Error::from)
+            .and_then(|l| string_encoding.decode(l.strip_suffix(b"\r").unwrap_or(&l)))
+            .with_context(|| format!("read line {}", line_num))?;
+
+        let tokens = tokenize(&line).with_context(|| format!("read line {}", line_num))?;
+        let (keyword, args) = match tokens.split_first() {
+            Some((k, a)) if !k.starts_with(';') => (k.as_str(), a),
+            _ => continue,
+        };
+
+        let mut args = Args::new(keyword, args);
+        (|| -> Result<()> {
+            match keyword {
+                "model" => {
+                    model = Some(Model {
+                        path: args.next()?,
+                        root: args.next()?,
+                    });
+                }
+                "default_sync" => sync = Some((args.next_type()?, args.next()?)),
+                "default_non_sync" => non_sync = Some((args.next_type()?, args.next()?)),
+                "anim" => anims.push(Animation {
+                    id: args.next()?,
+                    path: args.next()?,
+                    index: args.next()?,
+                    trans: Vec::new(),
+                }),
+                "tran" => {
+                    let anim = anims.last_mut().context("`tran` outside of `anim`")?;
+                    let id = args.next()?;
+                    let type_ = args.next_type()?;
+                    let ext = if args.is_empty() {
+                        None
+                    } else {
+                        Some(TransitionExt {
+                            duration: args.next()?,
+                            intermediate_anims: Vec::new(),
+                            chain_anims: Vec::new(),
+                        })
+                    };
+                    anim.trans.push(Transition { id, type_, ext });
+                }
+                "intermediate" => {
+                    let ext = last_tran_ext(&mut anims)?;
+                    ext.intermediate_anims.push(IntermediateAnimation {
+                        start_key: args.next()?,
+                        target_key: args.next()?,
+                    });
+                }
+                "chain" => {
+                    let ext = last_tran_ext(&mut anims)?;
+                    ext.chain_anims.push(ChainAnimation {
+                        id: args.next()?,
+                        duration: args.next()?,
+                    });
+                }
+                "layer_group" => layer_groups.push(LayerGroup {
+                    id: args.next()?,
+                    name: args.next()?,
+                    layers: Vec::new(),
+                }),
+                "layer" => {
+                    let layer_group = layer_groups
+                        .last_mut()
+                        .context("`layer` outside of `layer_group`")?;
+                    layer_group.layers.push(Layer {
+                        id: args.next()?,
+                        priority: args.next()?,
+                        weight: args.next()?,
+                        ease_in_time: args.next()?,
+                        ease_out_time: args.next()?,
+                        sync_id: args.next()?,
+                    });
+                }
+                _ => bail!("unknown record `{}`", keyword),
+            }
+            args.finish()
+        })()
+        .with_context(|| format!("read line {}", line_num))?;
+    }
+
+    let (sync_type, sync_duration) = sync.context("missing `default_sync`")?;
+    let (non_sync_type, non_sync_duration) = non_sync.context("missing `default_non_sync`")?;
+
+    let body = SourceFileBody {
+        model: model.context("missing `model`")?,
+        default_trans: DefaultTransitions {
+            sync_type,
+            sync_duration,
+            non_sync_type,
+            non_sync_duration,
+        },
+        anims,
+        layer_groups,
+    };
+
+    Ok(SourceFile { header, body })
+}
+
+fn decode_header(bytes: &[u8], string_encoding: StringEncoding) -> Result<SourceFileHeader> {
+    let version = *bytes.first().context("read `version`")?;
+
+    let format_version = match parse_magic(&bytes[1..]) {
+        Some(v) => v,
+        None => bail!(
+            "unexpected `magic`: `{}`",
+            String::from_utf8_lossy(&bytes[1..])
+        ),
+    };
+
+    Ok(SourceFileHeader {
+        version,
+        format_version,
+        is_little_endian: true,
+        is_text: true,
+        string_encoding,
+    })
+}
+
+fn last_tran_ext(anims: &mut [Animation]) -> Result<&mut TransitionExt> {
+    anims
+        .last_mut()
+        .and_then(|a| a.trans.last_mut())
+        .context("record outside of `tran`")?
+        .ext
+        .as_mut()
+        .context("record in `tran` without duration")
+}
+
+/// Splits a line into whitespace-separated tokens, keeping quoted strings together.
+fn tokenize(line: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+
+        let mut token = String::new();
+        if c == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => token.push(c),
+                    None => bail!("unterminated string"),
+                }
+            }
+        } else {
+            token.push(c);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                token.push(c);
+            }
+        }
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn quote(s: &str) -> Result<String> {
+    if s.contains(['"', '\n', '\r']) {
+        bail!("`{}` cannot be written as a quoted string", s);
+    }
+    Ok(format!("\"{}\"", s))
+}
+
+pub(crate) fn type_name(type_: TransitionType) -> &'static str {
+    match type_ {
+        TransitionType::Blend => "blend",
+        TransitionType::Morph => "morph",
+        TransitionType::Crossfade => "crossfade",
+        TransitionType::ChainAnimation => "chain_animation",
+        TransitionType::DefaultSync => "default_sync",
+        TransitionType::DefaultNonSync => "default_non_sync",
+    }
+}
+
+pub(crate) fn parse_type_name(name: &str) -> Option<TransitionType> {
+    let type_ = match name {
+        "blend" => TransitionType::Blend,
+        "morph" => TransitionType::Morph,
+        "crossfade" => TransitionType::Crossfade,
+        "chain_animation" => TransitionType::ChainAnimation,
+        "default_sync" => TransitionType::DefaultSync,
+        "default_non_sync" => TransitionType::DefaultNonSync,
+        _ => return None,
+    };
+    Some(type_)
+}
+
+/// Sequential access to the arguments of a record.
+struct Args<'a> {
+    keyword: &'a str,
+    iter: std::slice::Iter<'a, String>,
+}
+
+impl<'a> Args<'a> {
+    fn new(keyword: &'a str, args: &'a [String]) -> Self {
+        Self {
+            keyword,
+            iter: args.iter(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.iter.len() == 0
+    }
+
+    fn next<T>(&mut self) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        let arg = self
+            .iter
+            .next()
+            .with_context(|| format!("missing argument of `{}`", self.keyword))?;
+        arg.parse()
+            .with_context(|| format!("parse argument `{}`", arg))
+    }
+
+    fn next_type(&mut self) -> Result<TransitionType> {
+        let name: String = self.next()?;
+        parse_type_name(&name).with_context(|| format!("unknown trans type: `{}`", name))
+    }
+
+    fn finish(&self) -> Result<()> {
+        if !self.is_empty() {
+            bail!("too many arguments of `{}`", self.keyword);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_source_file, encode_source_file, is_text_kfm};
+    use crate::bin::StringEncoding;
+    use crate::source::TransitionType;
+    use indoc::indoc;
+
+    const TEXT: &str = indoc! {r#"
+        ;Gamebryo KFM File Version 2.2.0.0a
+        model "./../../mesh/newenemies/mech_order_darkling_1.nif" "Accumulation_Root"
+        default_sync morph 0.25
+        default_non_sync blend 0.25
+        anim 0 ".\mech\mech_gunbot_m_idle.kf" 0
+          tran 1 default_sync
+          tran 2 chain_animation 0.5
+            intermediate "start" "end"
+            chain 1 0.1
+        anim 1 "./mech/mech_gunbot_m_run.kf" 1
+        anim 2 "./mech/mech_gunbot_a_attack.kf" 0
+        layer_group 0 "upper body"
+          layer 0 -1 0.75 0.1 0.2 4294967295
+    "#};
+
+    #[test]
+    fn test_text_decode() {
+        assert!(is_text_kfm(TEXT.as_bytes()));
+
+        let src_file = decode_source_file(TEXT.as_bytes(), StringEncoding::default()).unwrap();
+        assert!(src_file.header.is_text);
+        assert_eq!(b';', src_file.header.version);
+
+        let body = &src_file.body;
+        assert_eq!("Accumulation_Root", body.model.root);
+        assert_eq!(3, body.anims.len());
+        assert_eq!(".\\mech\\mech_gunbot_m_idle.kf", body.anims[0].path);
+        assert_eq!(1, body.anims[1].index);
+
+        let tran = &body.anims[0].trans[1];
+        assert_eq!(TransitionType::ChainAnimation, tran.type_);
+        let ext = tran.ext.as_ref().unwrap();
+        assert_eq!(0.5, ext.duration);
+        assert_eq!("end", ext.intermediate_anims[0].target_key);
+        assert_eq!(1, ext.chain_anims[0].id);
+
+        let layer = &body.layer_groups[0].layers[0];
+        assert_eq!("upper body", body.layer_groups[0].name);
+        assert_eq!(-1, layer.priority);
+        assert_eq!(u32::MAX, layer.sync_id);
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let src_file = decode_source_file(TEXT.as_bytes(), StringEncoding::default()).unwrap();
+
+        let mut actual = Vec::new();
+        encode_source_file(&mut actual, &src_file).unwrap();
+        assert_eq!(TEXT, String::from_utf8(actual).unwrap());
+    }
+
+    #[test]
+    fn test_text_decode_errors() {
+        let text = TEXT.replace("tran 1 default_sync", "tran 1 default_sync 0.5 1");
+        let err = decode_source_file(text.as_bytes(), StringEncoding::default()).unwrap_err();
+        assert_eq!("read line 6", err.to_string());
+
+        let text = TEXT.replace("2.2.0.0a", "2.2.0.0b");
+        assert!(!is_text_kfm(text.as_bytes()));
+        assert!(decode_source_file(text.as_bytes(), StringEncoding::default()).is_err());
+
+        let text = TEXT.replacen(';', "\u{e9}", 1);
+        assert!(!is_text_kfm(text.as_bytes()));
+        let err = decode_source_file(text.as_bytes(), StringEncoding::default()).unwrap_err();
+        assert!(format!("{:#}", err).starts_with("read header: unexpected `magic`"));
+    }
+
+    #[test]
+    fn test_text_string_encoding() {
+        let text = TEXT.replace("upper body", "t\u{eb}te");
+        let encoding = StringEncoding::Windows1252;
+        let bytes = encoding.encode(&text).unwrap();
+        assert_eq!(text.len() - 1, bytes.len());
+
+        let src_file = decode_source_file(&bytes[..], encoding).unwrap();
+        assert_eq!("t\u{eb}te", src_file.body.layer_groups[0].name);
+        assert_eq!(encoding, src_file.header.string_encoding);
+
+        let mut actual = Vec::new();
+        encode_source_file(&mut actual, &src_file).unwrap();
+        assert_eq!(bytes, actual);
+
+        // UTF-8 input is read as UTF-8 by default, and rejected as Windows-1252 is not
+        let src_file = decode_source_file(text.as_bytes(), StringEncoding::default()).unwrap();
+        assert_eq!("t\u{eb}te", src_file.body.layer_groups[0].name);
+        let err = decode_source_file(&bytes[..], StringEncoding::default()).unwrap_err();
+        assert_eq!("read line 12", err.to_string());
+
+        let mut src_file = src_file;
+        src_file.header.string_encoding = StringEncoding::Ascii;
+        let err = encode_source_file(&mut Vec::new(), &src_file).unwrap_err();
+        assert_eq!("write line 12", err.to_string());
+    }
+
+    #[test]
+    fn test_text_crlf() {
+        let text = TEXT.replace('\n', "\r\n");
+        assert!(is_text_kfm(text.as_bytes()));
+
+        let src_file = decode_source_file(text.as_bytes(), StringEncoding::default()).unwrap();
+        assert_eq!(3, src_file.body.anims.len());
+        assert_eq!("upper body", src_file.body.layer_groups[0].name);
+    }
+}
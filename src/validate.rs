@@ -0,0 +1,551 @@
+use crate::nif::{ControllerSequence, KfFile};
+use crate::source::{Animation, SourceFileBody};
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A problem found in a source file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub location: Location,
+    pub message: String,
+}
+
+impl Problem {
+    fn error(location: Location, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            location,
+            message,
+        }
+    }
+
+    fn warning(location: Location, message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            location,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.location, self.message)
+    }
+}
+
+/// Where in a source file a problem was found.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Location {
+    /// The default transitions.
+    DefaultTrans,
+    /// An animation, by its id.
+    Anim(u32),
+    /// A transition, by the ids of its animation and its target animation.
+    Trans(u32, u32),
+    /// A layer, by the id of its layer group and its own id.
+    Layer(u32, u32),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DefaultTrans => f.write_str("default trans"),
+            Self::Anim(anim_id) => write!(f, "anim {}", anim_id),
+            Self::Trans(anim_id, trans_id) => write!(f, "anim {}, trans {}", anim_id, trans_id),
+            Self::Layer(group_id, layer_id) => {
+                write!(f, "layer group {}, layer {}", group_id, layer_id)
+            }
+        }
+    }
+}
+
+/// How bad a problem is.
+///
+/// Errors make the file unusable or corrupt it when it is written, while warnings point at
+/// things that are likely mistakes.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Warning => f.write_str("warning"),
+            Self::Error => f.write_str("error"),
+        }
+    }
+}
+
+/// Checks that the ids in a source file refer to existing animations, including the ids and
+/// sync ids of layers, that each transition's `ext` is present exactly when its type has one,
+/// and that the default transitions have a valid type and duration.
+pub fn check_integrity(body: &SourceFileBody) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    for e in body.default_trans.check() {
+        problems.push(Problem::error(Location::DefaultTrans, e.to_string()));
+    }
+
+    let mut anim_ids = HashSet::new();
+    for anim in body.anims.iter() {
+        if !anim_ids.insert(anim.id) {
+            let message = "duplicate anim id".to_string();
+            problems.push(Problem::error(Location::Anim(anim.id), message));
+        }
+    }
+
+    for anim in body.anims.iter() {
+        let mut trans_ids = HashSet::new();
+        for tran in anim.trans.iter() {
+            let location = Location::Trans(anim.id, tran.id);
+
+            if !trans_ids.insert(tran.id) {
+                let message = "duplicate trans id".to_string();
+                problems.push(Problem::error(location, message));
+            }
+            if !anim_ids.contains(&tran.id) {
+                let message = format!("target anim {} does not exist", tran.id);
+                problems.push(Problem::error(location, message));
+            }
+            if tran.id == anim.id {
+                let message = "transition to the anim itself".to_string();
+                problems.push(Problem::warning(location, message));
+            }
+
+            if let Err(e) = tran.check_ext() {
+                problems.push(Problem::error(location, e.to_string()));
+            }
+
+            for chain_anim in tran.ext.iter().flat_map(|ext| ext.chain_anims.iter()) {
+                if !anim_ids.contains(&chain_anim.id) {
+                    let message = format!("chain anim {} does not exist", chain_anim.id);
+                    problems.push(Problem::error(location, message));
+                }
+            }
+        }
+    }
+
+    for group in body.layer_groups.iter() {
+        for layer in group.layers.iter() {
+            let location = Location::Layer(group.id, layer.id);
+            if !anim_ids.contains(&layer.id) {
+                let message = format!("anim {} does not exist", layer.id);
+                problems.push(Problem::error(location, message));
+            }
+            // The maximum id stands for no sync anim
+            if layer.sync_id != u32::MAX && !anim_ids.contains(&layer.sync_id) {
+                let message = format!("sync anim {} does not exist", layer.sync_id);
+                problems.push(Problem::error(location, message));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Resolves the path of an animation's `.kf` file relative to the directory of the `.kfm` file.
+pub fn resolve_kf_path(kfm_dir: &Path, anim_path: &str) -> PathBuf {
+    kfm_dir.join(anim_path.replace("\\", "/"))
+}
+
+/// Checks that the keys of the intermediate animations of all transitions exist as text keys
+/// in the source and target sequences.
+///
+/// `load_kf` is called once for each distinct `.kf` file path of the animations. Animations
+/// whose file cannot be loaded, or whose `index` selects no sequence of it, are reported too.
+pub fn check_text_keys<F>(body: &SourceFileBody, mut load_kf: F) -> Vec<Problem>
+where
+    F: FnMut(&str) -> Result<KfFile>,
+{
+    let mut problems = Vec::new();
+
+    let mut kf_files = HashMap::new();
+    for anim in body.anims.iter() {
+        kf_files
+            .entry(anim.path.as_str())
+            .or_insert_with(|| load_kf(&anim.path).map_err(|e| format!("{:#}", e)));
+    }
+
+    // Look up the sequence of each animation
+    let mut sequences = HashMap::new();
+    for anim in body.anims.iter() {
+        match &kf_files[anim.path.as_str()] {
+            Ok(kf_file) => match kf_file.sequences.get(anim.index as usize) {
+                Some(seq) => {
+                    sequences.insert(anim.id, seq);
+                }
+                None => problems.push(Problem::error(
+                    Location::Anim(anim.id),
+                    format!(
+                        "index {} is out of range, `{}` has {} sequences",
+                        anim.index,
+                        anim.path,
+                        kf_file.sequences.len()
+                    ),
+                )),
+            },
+            Err(e) => problems.push(Problem::warning(
+                Location::Anim(anim.id),
+                format!("cannot load `{}`: {}", anim.path, e),
+            )),
+        }
+    }
+
+    for anim in body.anims.iter() {
+        check_anim_text_keys(anim, &sequences, &mut problems);
+    }
+
+    problems
+}
+
+fn check_anim_text_keys(
+    anim: &Animation,
+    sequences: &HashMap<u32, &ControllerSequence>,
+    problems: &mut Vec<Problem>,
+) {
+    for tran in anim.trans.iter() {
+        let Some(ext) = &tran.ext else {
+            continue;
+        };
+
+        for inter in ext.intermediate_anims.iter() {
+            let keys = [
+                ("start", &inter.start_key, sequences.get(&anim.id)),
+                ("target", &inter.target_key, sequences.get(&tran.id)),
+            ];
+            for (kind, key, seq) in keys {
+                // Sequences that could not be looked up are reported already
+                let Some(seq) = seq else {
+                    continue;
+                };
+                if !seq.has_text_key(key) {
+                    let message = format!(
+                        "{} key `{}` is not a text key of sequence `{}`",
+                        kind, key, seq.name
+                    );
+                    let location = Location::Trans(anim.id, tran.id);
+                    problems.push(Problem::error(location, message));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_integrity, check_text_keys, resolve_kf_path, Location, Problem};
+    use crate::bin::{Limits, StringEncoding};
+    use crate::nif::tests::make_kf;
+    use crate::nif::KfFile;
+    use crate::source::{Animation, DefaultTransitions, Model, SourceFileBody};
+    use crate::source::{ChainAnimation, IntermediateAnimation, Layer, LayerGroup};
+    use crate::source::{SourceFile, SourceFormat};
+    use crate::source::{Transition, TransitionExt, TransitionType};
+    use anyhow::bail;
+    use indoc::indoc;
+    use std::path::Path;
+
+    fn make_anim(id: u32, path: &str, index: u32, trans: Vec<Transition>) -> Animation {
+        Animation {
+            id,
+            path: path.to_string(),
+            index,
+            trans,
+        }
+    }
+
+    fn make_tran(id: u32, keys: &[(&str, &str)]) -> Transition {
+        Transition {
+            id,
+            type_: TransitionType::Morph,
+            ext: Some(TransitionExt {
+                duration: 0.25,
+                intermediate_anims: keys
+                    .iter()
+                    .map(|(s, t)| IntermediateAnimation {
+                        start_key: s.to_string(),
+                        target_key: t.to_string(),
+                    })
+                    .collect(),
+                chain_anims: Vec::new(),
+            }),
+        }
+    }
+
+    fn make_body(anims: Vec<Animation>) -> SourceFileBody {
+        SourceFileBody {
+            model: Model {
+                path: "model.nif".to_string(),
+                root: "Root".to_string(),
+            },
+            default_trans: DefaultTransitions {
+                sync_type: TransitionType::Morph,
+                sync_duration: 0.25,
+                non_sync_type: TransitionType::Blend,
+                non_sync_duration: 0.25,
+            },
+            anims,
+            layer_groups: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_check_integrity() {
+        let mut default_with_ext = make_tran(0, &[]);
+        default_with_ext.type_ = TransitionType::DefaultSync;
+        let mut morph_without_ext = make_tran(2, &[]);
+        morph_without_ext.ext = None;
+        let mut chain = make_tran(1, &[]);
+        chain.type_ = TransitionType::ChainAnimation;
+        chain.ext.as_mut().unwrap().chain_anims = vec![
+            ChainAnimation {
+                id: 0,
+                duration: 0.1,
+            },
+            ChainAnimation {
+                id: 7,
+                duration: 0.1,
+            },
+        ];
+
+        let body = make_body(vec![
+            make_anim(0, "idle.kf", 0, vec![make_tran(1, &[]), make_tran(1, &[])]),
+            make_anim(1, "run.kf", 0, vec![default_with_ext, morph_without_ext]),
+            make_anim(2, "hit.kf", 0, vec![make_tran(2, &[]), make_tran(5, &[])]),
+            make_anim(2, "hit.kf", 0, vec![chain]),
+        ]);
+
+        let expected = vec![
+            Problem::error(Location::Anim(2), "duplicate anim id".to_string()),
+            Problem::error(Location::Trans(0, 1), "duplicate trans id".to_string()),
+            Problem::error(
+                Location::Trans(1, 0),
+                "`ext` is not stored for type `DefaultSync`".to_string(),
+            ),
+            Problem::error(
+                Location::Trans(1, 2),
+                "`ext` is required for type `Morph`".to_string(),
+            ),
+            Problem::warning(
+                Location::Trans(2, 2),
+                "transition to the anim itself".to_string(),
+            ),
+            Problem::error(
+                Location::Trans(2, 5),
+                "target anim 5 does not exist".to_string(),
+            ),
+            Problem::error(
+                Location::Trans(2, 1),
+                "chain anim 7 does not exist".to_string(),
+            ),
+        ];
+        assert_eq!(expected, check_integrity(&body));
+
+        let body = make_body(vec![make_anim(0, "idle.kf", 0, Vec::new())]);
+        assert!(check_integrity(&body).is_empty());
+    }
+
+    #[test]
+    fn test_check_integrity_of_default_trans() {
+        let mut body = make_body(Vec::new());
+        body.default_trans.sync_duration = f32::NAN;
+        body.default_trans.non_sync_type = TransitionType::ChainAnimation;
+        body.default_trans.non_sync_duration = -0.0;
+
+        let expected = vec![
+            Problem::error(
+                Location::DefaultTrans,
+                "`sync_duration` must be finite and non-negative, not `NaN`".to_string(),
+            ),
+            Problem::error(
+                Location::DefaultTrans,
+                "`non_sync_type` cannot be `ChainAnimation`".to_string(),
+            ),
+        ];
+        assert_eq!(expected, check_integrity(&body));
+        assert_eq!(
+            "error: default trans: `non_sync_type` cannot be `ChainAnimation`",
+            expected[1].to_string()
+        );
+    }
+
+    #[test]
+    fn test_check_integrity_of_layers() {
+        let make_layer = |id, sync_id| Layer {
+            id,
+            priority: 0,
+            weight: 1.0,
+            ease_in_time: 0.0,
+            ease_out_time: 0.0,
+            sync_id,
+        };
+
+        let mut body = make_body(vec![
+            make_anim(0, "idle.kf", 0, Vec::new()),
+            make_anim(1, "run.kf", 0, Vec::new()),
+        ]);
+        body.layer_groups = vec![LayerGroup {
+            id: 4,
+            name: "upper body".to_string(),
+            layers: vec![make_layer(0, 1), make_layer(1, u32::MAX), make_layer(2, 3)],
+        }];
+
+        // Layers of deleted anims are left behind
+        let expected = vec![
+            Problem::error(Location::Layer(4, 2), "anim 2 does not exist".to_string()),
+            Problem::error(
+                Location::Layer(4, 2),
+                "sync anim 3 does not exist".to_string(),
+            ),
+        ];
+        assert_eq!(expected, check_integrity(&body));
+        assert_eq!(
+            "error: layer group 4, layer 2: anim 2 does not exist",
+            expected[0].to_string()
+        );
+    }
+
+    #[test]
+    fn test_check_integrity_of_unchecked_yaml() {
+        let yaml = indoc! {"
+            header:
+              version: 59
+              is_little_endian: true
+            body:
+              model: { path: model.nif, root: Root }
+              default_trans:
+                sync_type: morph
+                sync_duration: 0.25
+                non_sync_type: blend
+                non_sync_duration: 0.25
+              anims:
+              - id: 0
+                path: idle.kf
+                index: 0
+                trans:
+                - { id: 1, type: blend }
+              - id: 1
+                path: run.kf
+                index: 0
+                trans:
+                - id: 0
+                  type: default_sync
+                  ext: { duration: 0.5, intermediate_anims: [], chain_anims: [] }
+              layer_groups: []
+        "};
+
+        // Loading fails on the first bad `ext`, so validation loads without the check
+        let limits = Limits::default();
+        let encoding = StringEncoding::default();
+        let result =
+            SourceFile::from_reader_with(yaml.as_bytes(), SourceFormat::Yaml, limits, encoding);
+        assert!(result.is_err());
+        let src_file = SourceFile::from_reader_unchecked(
+            yaml.as_bytes(),
+            SourceFormat::Yaml,
+            limits,
+            encoding,
+        )
+        .unwrap();
+
+        let expected = vec![
+            Problem::error(
+                Location::Trans(0, 1),
+                "`ext` is required for type `Blend`".to_string(),
+            ),
+            Problem::error(
+                Location::Trans(1, 0),
+                "`ext` is not stored for type `DefaultSync`".to_string(),
+            ),
+        ];
+        assert_eq!(expected, check_integrity(&src_file.body));
+    }
+
+    #[test]
+    fn test_check_text_keys() {
+        let body = make_body(vec![
+            make_anim(
+                0,
+                "idle.kf",
+                0,
+                vec![
+                    make_tran(
+                        1,
+                        &[
+                            ("idle_end", "run_start"),
+                            ("idle_end", "hit_start"),
+                            ("idle_typo", "run_start"),
+                        ],
+                    ),
+                    make_tran(2, &[("idle_typo", "idle_start")]),
+                ],
+            ),
+            make_anim(
+                1,
+                "run.kf",
+                1,
+                vec![make_tran(0, &[("run_end", "idle_start")])],
+            ),
+            make_anim(2, "idle.kf", 3, Vec::new()),
+            make_anim(3, "missing.kf", 0, Vec::new()),
+        ]);
+
+        let mut num_loads = 0;
+        let problems = check_text_keys(&body, |path| {
+            num_loads += 1;
+            let bytes = match path {
+                "idle.kf" => make_kf(&[("idle", &["idle_start", "idle_end"])]),
+                "run.kf" => make_kf(&[("walk", &[]), ("run", &["run_start", "run_end"])]),
+                _ => bail!("file not found"),
+            };
+            KfFile::from_reader(&bytes[..])
+        });
+        assert_eq!(3, num_loads);
+
+        let expected = vec![
+            Problem::error(
+                Location::Anim(2),
+                "index 3 is out of range, `idle.kf` has 1 sequences".to_string(),
+            ),
+            Problem::warning(
+                Location::Anim(3),
+                "cannot load `missing.kf`: file not found".to_string(),
+            ),
+            Problem::error(
+                Location::Trans(0, 1),
+                "target key `hit_start` is not a text key of sequence `run`".to_string(),
+            ),
+            Problem::error(
+                Location::Trans(0, 1),
+                "start key `idle_typo` is not a text key of sequence `idle`".to_string(),
+            ),
+            Problem::error(
+                Location::Trans(0, 2),
+                "start key `idle_typo` is not a text key of sequence `idle`".to_string(),
+            ),
+        ];
+        assert_eq!(expected, problems);
+        assert_eq!(
+            "error: anim 0, trans 2: start key `idle_typo` is not a text key of sequence `idle`",
+            problems[4].to_string()
+        );
+    }
+
+    #[test]
+    fn test_resolve_kf_path() {
+        assert_eq!(
+            Path::new("data/mech/./idle.kf"),
+            resolve_kf_path(Path::new("data/mech"), "./idle.kf")
+        );
+        assert_eq!(
+            Path::new("data/../anims/idle.kf"),
+            resolve_kf_path(Path::new("data"), "..\\anims\\idle.kf")
+        );
+    }
+}